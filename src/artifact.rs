@@ -0,0 +1,422 @@
+//! Looks inside compiled artifacts, which can be ELF, Mach-O or PE files
+
+use crate::platform::{Compatibility, Machine, Platform};
+use failure::{bail, format_err, Error, ResultExt};
+use goblin::elf::header::{ET_DYN, ET_EXEC};
+use goblin::elf::sym::{STB_GLOBAL, STB_WEAK};
+use goblin::elf::Elf;
+use goblin::mach::header::{MH_BUNDLE, MH_DYLIB, MH_EXECUTE};
+use goblin::mach::Mach;
+use goblin::Object;
+use maturin::BridgeModel;
+use std::fs;
+use std::path::Path;
+
+/// Checks that the artifact is what the bridge needs before it's packaged: a shared library, or
+/// an executable for bin, compiled for the platform, that exports the init function of the
+/// module for pyo3 and rust-cpython.
+///
+/// This catches rlibs, the library of another crate and artifacts of another target, which
+/// would otherwise only fail when the module is imported. What the artifact needs from the
+/// system is up to `check_compatibility`.
+pub fn check_artifact(
+    artifact_path: &Path,
+    platform: &Platform,
+    bridge: &BridgeModel,
+    module_name: &str,
+) -> Result<(), Error> {
+    let bytes =
+        fs::read(artifact_path).context(format!("Can't read {}", artifact_path.display()))?;
+    let object = Object::parse(&bytes).context(format!(
+        "{} is not an ELF, Mach-O or PE file",
+        artifact_path.display()
+    ))?;
+
+    let is_bin = *bridge == BridgeModel::Bin;
+    let (machine, kind_ok) = match &object {
+        Object::Elf(elf) => {
+            let kind_ok = match elf.header.e_type {
+                ET_EXEC => is_bin,
+                // Position independent executables are ET_DYN too, but have an interpreter
+                ET_DYN => elf.interpreter.is_some() == is_bin,
+                _ => false,
+            };
+            (Machine::Elf(elf.header.e_machine), kind_ok)
+        }
+        Object::Mach(Mach::Binary(macho)) => {
+            let kind_ok = match macho.header.filetype {
+                MH_EXECUTE => is_bin,
+                MH_DYLIB | MH_BUNDLE => !is_bin,
+                _ => false,
+            };
+            (Machine::MachO(macho.header.cputype), kind_ok)
+        }
+        Object::PE(pe) => (
+            Machine::Pe(pe.header.coff_header.machine),
+            pe.is_lib != is_bin,
+        ),
+        Object::Mach(Mach::Fat(_)) => bail!(
+            "{} is a universal Mach-O binary, please pass the library of a single target",
+            artifact_path.display()
+        ),
+        Object::Archive(_) => bail!(
+            "{} is a static library, probably an rlib or staticlib. \
+             Python needs a shared library, i.e. crate-type = [\"cdylib\"]",
+            artifact_path.display()
+        ),
+        Object::Unknown(_) => bail!(
+            "{} is not an ELF, Mach-O or PE file",
+            artifact_path.display()
+        ),
+    };
+
+    let expected = platform.machine();
+    if machine != expected {
+        bail!(
+            "{} is {}, but {} needs {}. Was it compiled for another target?",
+            artifact_path.display(),
+            describe_machine(machine),
+            platform.platform_tag(),
+            describe_machine(expected)
+        );
+    }
+    if !kind_ok {
+        match bridge {
+            BridgeModel::Bin => bail!("{} is not an executable", artifact_path.display()),
+            _ => bail!(
+                "{} is not a shared library. Python needs crate-type = [\"cdylib\"]",
+                artifact_path.display()
+            ),
+        }
+    }
+    if let BridgeModel::Bindings(_) = bridge {
+        // The init function is named after the module itself, without its packages
+        let name = module_name.rsplit('.').next().unwrap();
+        let init = format!("PyInit_{}", name);
+        let symbols = exported_symbols(&bytes)?;
+        if !symbols.contains(&init) {
+            let inits: Vec<&str> = symbols
+                .iter()
+                .map(String::as_str)
+                .filter(|symbol| symbol.starts_with("PyInit_"))
+                .collect();
+            match inits.as_slice() {
+                [] => bail!(
+                    "{} doesn't export {}. Is it the library of the right crate?",
+                    artifact_path.display(),
+                    init
+                ),
+                _ => bail!(
+                    "{} doesn't export {}, but {}. Does --module-name match the name of the \
+                     #[pymodule]?",
+                    artifact_path.display(),
+                    init,
+                    inits.join(", ")
+                ),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The newest versions of the libstdc++ and libgcc_s symbols each manylinux glibc version may
+/// need, from the manylinux policies of auditwheel: the glibc version and the newest GLIBCXX_,
+/// CXXABI_ and GCC_ version. A glibc version between two entries gets the older one.
+const TOOLCHAIN_VERSIONS: &[((u32, u32), &str, &str, &str)] = &[
+    ((2, 5), "3.4.9", "1.3.1", "4.2.0"),
+    ((2, 12), "3.4.13", "1.3.3", "4.5.0"),
+    ((2, 17), "3.4.19", "1.3.7", "4.8.0"),
+    ((2, 24), "3.4.22", "1.3.10", "4.8.0"),
+    ((2, 27), "3.4.24", "1.3.11", "7.0.0"),
+    ((2, 28), "3.4.25", "1.3.11", "7.0.0"),
+    ((2, 31), "3.4.28", "1.3.12", "7.0.0"),
+    ((2, 34), "3.4.29", "1.3.13", "7.0.0"),
+    ((2, 35), "3.4.30", "1.3.13", "12.0.0"),
+    ((2, 39), "3.4.33", "1.3.15", "14.0.0"),
+];
+
+/// Checks that an ELF file keeps the promise of the platform's compatibility: that it's linked
+/// against the right libc, needs no newer symbol versions from glibc, libstdc++ and libgcc_s
+/// than the compatibility guarantees and no libraries besides the system libraries of the
+/// compatibility and the given bundled ones.
+///
+/// musl doesn't version its symbols, so for musllinux only the libraries are checked. Files
+/// that aren't ELF files have nothing to check.
+pub fn check_compatibility(
+    path: &Path,
+    platform: &Platform,
+    bundled: &[String],
+) -> Result<(), Error> {
+    let compatibility = platform.compatibility();
+    if compatibility == Compatibility::Linux {
+        return Ok(());
+    }
+    let bytes = fs::read(path).context(format!("Can't read {}", path.display()))?;
+    let elf = match Object::parse(&bytes).context(format!("Failed to parse {}", path.display()))? {
+        Object::Elf(elf) => elf,
+        _ => return Ok(()),
+    };
+
+    let links_musl = elf
+        .libraries
+        .iter()
+        .any(|library| *library == "libc.so" || library.starts_with("libc.musl-"));
+    let links_glibc = elf.libraries.contains(&"libc.so.6");
+    let version_needs = version_needs(&elf, &bytes)?;
+    let needed_version = |prefix: &str| {
+        version_needs
+            .iter()
+            .filter_map(|name| name.strip_prefix(prefix))
+            .filter_map(parse_version)
+            .max()
+    };
+    // Only the major and minor version count, GLIBC_2.2.5 is from glibc 2.2
+    let glibc_version = needed_version("GLIBC_")
+        .map(|version| (version[0], version.get(1).copied().unwrap_or_default()));
+
+    match compatibility {
+        Compatibility::Manylinux(..) if links_musl => {
+            bail!("it is linked against musl, use --compatibility musllinux_1_2 instead")
+        }
+        Compatibility::Manylinux(major, minor) => {
+            match glibc_version {
+                Some((needed_major, needed_minor))
+                    if (needed_major, needed_minor) > (major, minor) =>
+                {
+                    bail!(
+                        "it needs symbols from glibc {}.{}, but manylinux_{}_{} only guarantees \
+                         glibc {}.{}. Use --compatibility manylinux_{}_{} or link against an \
+                         older glibc",
+                        needed_major,
+                        needed_minor,
+                        major,
+                        minor,
+                        major,
+                        minor,
+                        needed_major,
+                        needed_minor
+                    )
+                }
+                _ => {}
+            }
+            let toolchain = TOOLCHAIN_VERSIONS
+                .iter()
+                .rev()
+                .find(|(glibc, ..)| *glibc <= (major, minor))
+                .unwrap_or(&TOOLCHAIN_VERSIONS[0]);
+            let (_, glibcxx, cxxabi, gcc) = toolchain;
+            for (prefix, library, newest) in &[
+                ("GLIBCXX_", "libstdc++", glibcxx),
+                ("CXXABI_", "libstdc++", cxxabi),
+                ("GCC_", "libgcc_s", gcc),
+            ] {
+                if let Some(needed) = needed_version(prefix) {
+                    if needed > parse_version(newest).unwrap() {
+                        bail!(
+                            "it needs {}{} from {}, but manylinux_{}_{} only guarantees {}{}. \
+                             Link against an older {} or use a newer --compatibility",
+                            prefix,
+                            format_version(&needed),
+                            library,
+                            major,
+                            minor,
+                            prefix,
+                            newest,
+                            library
+                        )
+                    }
+                }
+            }
+        }
+        Compatibility::Musllinux(..) if links_glibc || glibc_version.is_some() => {
+            bail!("it is linked against glibc, use --compatibility manylinux_X_Y instead")
+        }
+        Compatibility::Musllinux(..) | Compatibility::Linux => {}
+    }
+
+    for library in &elf.libraries {
+        if !compatibility.is_system_library(library) && !bundled.iter().any(|name| name == library)
+        {
+            bail!(
+                "it links against {}, which {} doesn't guarantee. Bundle it with --bundle-libs \
+                 or use --compatibility linux",
+                library,
+                compatibility
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Parses versions like "2.17" or "3.4.19", but not names like "CXXABI_TM_1"
+fn parse_version(version: &str) -> Option<Vec<u32>> {
+    version
+        .split('.')
+        .map(|number| number.parse().ok())
+        .collect()
+}
+
+fn format_version(version: &[u32]) -> String {
+    version
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Returns the names of the versions in the version needs of an ELF file, e.g. GLIBC_2.17 or
+/// GLIBCXX_3.4.19
+fn version_needs(elf: &Elf, bytes: &[u8]) -> Result<Vec<String>, Error> {
+    let dynamic = match &elf.dynamic {
+        Some(dynamic) => dynamic,
+        None => return Ok(Vec::new()),
+    };
+    let read = |offset: usize, size: usize| -> Result<u32, Error> {
+        let field = bytes
+            .get(offset..offset + size)
+            .ok_or_else(|| format_err!("the version needs of the ELF file are truncated"))?;
+        let mut value = 0;
+        for i in 0..size {
+            let byte = if elf.little_endian {
+                field[size - 1 - i]
+            } else {
+                field[i]
+            };
+            value = value << 8 | u32::from(byte);
+        }
+        Ok(value)
+    };
+
+    // Each Elf_Verneed names a library and points to a list of Elf_Vernaux, which name the
+    // versions needed from it. Both have the same layout for 32 and 64 bit.
+    let mut versions = Vec::new();
+    let mut verneed = dynamic.info.verneed as usize;
+    for _ in 0..dynamic.info.verneednum {
+        let count = read(verneed + 2, 2)?;
+        let mut vernaux = verneed + read(verneed + 8, 4)? as usize;
+        for _ in 0..count {
+            let name = read(vernaux + 8, 4)? as usize;
+            if let Some(Ok(name)) = elf.dynstrtab.get(name) {
+                versions.push(name.to_string());
+            }
+            vernaux += read(vernaux + 12, 4)? as usize;
+        }
+        let next = read(verneed + 12, 4)? as usize;
+        if next == 0 {
+            break;
+        }
+        verneed += next;
+    }
+    Ok(versions)
+}
+
+fn describe_machine(machine: Machine) -> String {
+    match machine {
+        Machine::Elf(machine) => format!("ELF with machine type {}", machine),
+        Machine::MachO(cputype) => format!("Mach-O with cpu type {:#x}", cputype),
+        Machine::Pe(machine) => format!("PE with machine type {:#x}", machine),
+    }
+}
+
+/// Returns the names of the symbols a shared library exports, without the leading underscore
+/// Mach-O adds to C symbols
+pub fn exported_symbols(bytes: &[u8]) -> Result<Vec<String>, Error> {
+    let symbols = match Object::parse(bytes)? {
+        Object::Elf(elf) => elf
+            .dynsyms
+            .iter()
+            .filter(|sym| {
+                let bind = sym.st_bind();
+                (bind == STB_GLOBAL || bind == STB_WEAK) && sym.st_shndx != 0
+            })
+            .filter_map(|sym| elf.dynstrtab.get(sym.st_name))
+            .collect::<Result<Vec<&str>, _>>()?
+            .into_iter()
+            .map(ToString::to_string)
+            .collect(),
+        Object::Mach(Mach::Binary(macho)) => macho
+            .exports()?
+            .into_iter()
+            .map(|export| match export.name.strip_prefix('_') {
+                Some(name) => name.to_string(),
+                None => export.name,
+            })
+            .collect(),
+        Object::PE(pe) => pe
+            .exports
+            .iter()
+            .filter_map(|export| export.name)
+            .map(ToString::to_string)
+            .collect(),
+        Object::Mach(Mach::Fat(_)) => bail!("universal Mach-O binaries are not supported"),
+        Object::Archive(_) => bail!("this is a static library, not a shared library"),
+        Object::Unknown(magic) => bail!("unknown file format with magic {:#x}", magic),
+    };
+    Ok(symbols)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::compile_library;
+    use tempfile::TempDir;
+
+    fn manylinux(major: u32, minor: u32) -> Platform {
+        Platform::from_triple("x86_64-unknown-linux-gnu")
+            .unwrap()
+            .with_compatibility(Compatibility::Manylinux(major, minor))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_rejects_libraries_that_are_neither_system_libraries_nor_bundled() {
+        let dir = TempDir::new().unwrap();
+        let dir_arg = format!("-L{}", dir.path().display());
+        compile_library(dir.path(), "libfoo.so", "int foo() { return 1; }", &[]);
+        let library = compile_library(
+            dir.path(),
+            "libmodule.so",
+            "int foo(); int bar() { return foo(); }",
+            &[&dir_arg, "-lfoo"],
+        );
+
+        let error = check_compatibility(&library, &manylinux(2, 17), &[]).unwrap_err();
+        assert!(error.to_string().contains("libfoo.so"), "{}", error);
+        check_compatibility(&library, &manylinux(2, 17), &["libfoo.so".to_string()]).unwrap();
+    }
+
+    #[test]
+    fn test_checks_the_glibc_version() {
+        let dir = TempDir::new().unwrap();
+        // getrandom is new in glibc 2.25
+        let library = compile_library(
+            dir.path(),
+            "libmodule.so",
+            "#include <sys/random.h>\n\
+             long bar(char *buf) { return getrandom(buf, 1, 0); }",
+            &[],
+        );
+
+        let error = check_compatibility(&library, &manylinux(2, 17), &[]).unwrap_err();
+        assert!(error.to_string().contains("glibc 2.25"), "{}", error);
+        check_compatibility(&library, &manylinux(2, 28), &[]).unwrap();
+    }
+
+    #[test]
+    fn test_checks_the_libstdcxx_version() {
+        let dir = TempDir::new().unwrap();
+        // Added in GLIBCXX_3.4.29, i.e. gcc 11
+        let library = compile_library(
+            dir.path(),
+            "libmodule.so",
+            "void _ZSt28__throw_bad_array_new_lengthv(void);\n\
+             void bar() { _ZSt28__throw_bad_array_new_lengthv(); }",
+            &["-lstdc++"],
+        );
+
+        let error = check_compatibility(&library, &manylinux(2, 28), &[]).unwrap_err();
+        assert!(error.to_string().contains("GLIBCXX_3.4.29"), "{}", error);
+        check_compatibility(&library, &manylinux(2, 34), &[]).unwrap();
+    }
+}
@@ -0,0 +1,489 @@
+//! Bundles the shared libraries an ELF artifact needs into the wheel, like `auditwheel repair`.
+//!
+//! Artifacts built with nix find their libraries in the store through their RUNPATH. Those that
+//! aren't part of every linux system are copied into a `<package>.libs` directory with the hash
+//! of their contents in the name, so they can't clash with other copies of the same library,
+//! and the RUNPATH is pointed at that directory.
+//!
+//! Instead of growing the dynamic string table like patchelf, the new names and RUNPATH are
+//! written where the old RPATH and RUNPATH strings were. Those hold at least one store path per
+//! library found through them, which is always long enough.
+
+use crate::platform::Compatibility;
+use failure::{bail, format_err, Error, ResultExt};
+use goblin::elf::dynamic::{DT_NEEDED, DT_RPATH, DT_RUNPATH, DT_SONAME};
+use goblin::elf::program_header::PT_DYNAMIC;
+use goblin::elf::Elf;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryInto;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where libraries are searched for when the RUNPATH doesn't have them
+const DEFAULT_LIBRARY_DIRS: &[&str] = &["/lib64", "/usr/lib64", "/lib", "/usr/lib"];
+
+/// A library copied next to the wheel contents
+pub struct BundledLibrary {
+    /// The name it had, e.g. "libz.so.1"
+    pub original_name: String,
+    /// Where it was found
+    pub source: PathBuf,
+    /// The name in the wheel, e.g. "libz-8f3c2a1e.so.1"
+    pub name: String,
+    /// The patched copy to put into the wheel
+    pub path: PathBuf,
+}
+
+/// Copies the artifact and the libraries it needs that aren't system libraries of the
+/// compatibility into the given directory, with their dependencies renamed to the bundled copies.
+///
+/// The artifact gets the given RUNPATH, which must lead from it to the bundled libraries inside
+/// the wheel, e.g. "$ORIGIN/../mypkg.libs". The libraries find each other with "$ORIGIN".
+/// Returns the path of the artifact's copy, which keeps its file name, and the libraries.
+pub fn bundle_libraries(
+    artifact_path: &Path,
+    runpath: &str,
+    compatibility: Compatibility,
+    dir: &Path,
+) -> Result<(PathBuf, Vec<BundledLibrary>), Error> {
+    let artifact =
+        fs::read(artifact_path).context(format!("Can't read {}", artifact_path.display()))?;
+
+    // Maps the names in DT_NEEDED to the libraries, found breadth first
+    let mut libraries: BTreeMap<String, (PathBuf, Vec<u8>)> = BTreeMap::new();
+    let mut queue = VecDeque::new();
+    queue.push_back((artifact_path.to_path_buf(), artifact.clone()));
+    while let Some((path, bytes)) = queue.pop_front() {
+        let elf = Elf::parse(&bytes).context(format!("Failed to parse {}", path.display()))?;
+        let search_dirs = search_dirs(&elf, &path);
+        for needed in &elf.libraries {
+            if compatibility.is_system_library(needed) || libraries.contains_key(*needed) {
+                continue;
+            }
+            let found = search_dirs
+                .iter()
+                .map(|dir| dir.join(needed))
+                .find(|candidate| candidate.is_file())
+                .ok_or_else(|| {
+                    format_err!(
+                        "Can't find {}, which {} needs, in {}",
+                        needed,
+                        path.display(),
+                        search_dirs
+                            .iter()
+                            .map(|dir| dir.display().to_string())
+                            .collect::<Vec<_>>()
+                            .join(":")
+                    )
+                })?;
+            let library = fs::read(&found).context(format!("Can't read {}", found.display()))?;
+            libraries.insert(needed.to_string(), (found.clone(), library.clone()));
+            queue.push_back((found, library));
+        }
+    }
+
+    let renames: BTreeMap<String, String> = libraries
+        .iter()
+        .map(|(name, (_, bytes))| (name.clone(), hashed_name(name, bytes)))
+        .collect();
+
+    fs::create_dir_all(dir)?;
+    let mut artifact = artifact;
+    patch_dynamic(&mut artifact, &renames, None, runpath)
+        .context(format!("Failed to patch {}", artifact_path.display()))?;
+    let artifact_copy = dir.join(artifact_path.file_name().unwrap());
+    fs::write(&artifact_copy, &artifact)
+        .context(format!("Failed to write {}", artifact_copy.display()))?;
+
+    let mut bundled = Vec::new();
+    for (original_name, (source, mut bytes)) in libraries {
+        let name = renames[&original_name].clone();
+        patch_dynamic(&mut bytes, &renames, Some(&name), "$ORIGIN")
+            .context(format!("Failed to patch {}", source.display()))?;
+        let path = dir.join(&name);
+        fs::write(&path, &bytes).context(format!("Failed to write {}", path.display()))?;
+        bundled.push(BundledLibrary {
+            original_name,
+            source,
+            name,
+            path,
+        });
+    }
+    Ok((artifact_copy, bundled))
+}
+
+/// The directories the dynamic linker would search for the dependencies of the file: its
+/// RUNPATH, or RPATH if there is none, and then the default directories
+fn search_dirs(elf: &Elf, path: &Path) -> Vec<PathBuf> {
+    let origin = path
+        .parent()
+        .map_or_else(PathBuf::new, Path::to_path_buf)
+        .to_string_lossy()
+        .to_string();
+    let rpaths = |tag| -> Vec<&str> {
+        elf.dynamic
+            .iter()
+            .flat_map(|dynamic| &dynamic.dyns)
+            .filter(|dyn_| dyn_.d_tag == tag)
+            .filter_map(|dyn_| elf.dynstrtab.get(dyn_.d_val as usize))
+            .filter_map(Result::ok)
+            .collect()
+    };
+    let mut entries = rpaths(DT_RUNPATH);
+    if entries.is_empty() {
+        entries = rpaths(DT_RPATH);
+    }
+    entries
+        .iter()
+        .flat_map(|rpath| rpath.split(':'))
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            PathBuf::from(
+                entry
+                    .replace("${ORIGIN}", &origin)
+                    .replace("$ORIGIN", &origin),
+            )
+        })
+        .chain(DEFAULT_LIBRARY_DIRS.iter().map(PathBuf::from))
+        .collect()
+}
+
+/// Adds the start of the sha256 of the contents to the name, e.g. "libz.so.1" becomes
+/// "libz-8f3c2a1e.so.1"
+fn hashed_name(name: &str, bytes: &[u8]) -> String {
+    let hash = format!("{:x}", Sha256::digest(bytes));
+    match name.find(".so") {
+        Some(i) => format!("{}-{}{}", &name[..i], &hash[..8], &name[i..]),
+        None => format!("{}-{}", name, &hash[..8]),
+    }
+}
+
+/// Renames the needed libraries, sets the soname if there's room for it and replaces RPATH and
+/// RUNPATH with the given one.
+///
+/// The strings are written into the space of the old RPATH and RUNPATH strings in the dynamic
+/// string table, and the dynamic entries are pointed at them. The version needs name the
+/// library they belong to as well, which the dynamic linker looks up among the loaded ones, so
+/// those of renamed libraries are pointed at the new names too, like `patchelf --replace-needed`
+/// does.
+fn patch_dynamic(
+    bytes: &mut [u8],
+    renames: &BTreeMap<String, String>,
+    soname: Option<&str>,
+    runpath: &str,
+) -> Result<(), Error> {
+    // Collected first since the elf borrows the bytes
+    let (word_size, little_endian, strtab_offset, entries, version_files, regions) = {
+        let elf = Elf::parse(bytes)?;
+        let dynamic = match &elf.dynamic {
+            Some(dynamic) => dynamic,
+            None => bail!("it has no dynamic section"),
+        };
+        let dynamic_offset = elf
+            .program_headers
+            .iter()
+            .find(|header| header.p_type == PT_DYNAMIC)
+            .map(|header| header.p_offset as usize)
+            .ok_or_else(|| format_err!("it has no PT_DYNAMIC segment"))?;
+        let entry_size = if elf.is_64 { 16 } else { 8 };
+        let strtab_offset = dynamic.info.strtab;
+
+        // The tag, the offset of the value in the file and the string it refers to
+        let mut entries = Vec::new();
+        let mut regions = Vec::new();
+        for (i, dyn_) in dynamic.dyns.iter().enumerate() {
+            if ![DT_NEEDED, DT_SONAME, DT_RPATH, DT_RUNPATH].contains(&dyn_.d_tag) {
+                continue;
+            }
+            let string = match elf.dynstrtab.get(dyn_.d_val as usize) {
+                Some(Ok(string)) => string.to_string(),
+                _ => bail!("a dynamic entry points outside of the string table"),
+            };
+            if dyn_.d_tag == DT_RPATH || dyn_.d_tag == DT_RUNPATH {
+                let start = strtab_offset + dyn_.d_val as usize;
+                regions.push((start, start + string.len() + 1));
+            }
+            let value_offset = dynamic_offset + i * entry_size + entry_size / 2;
+            entries.push((dyn_.d_tag, value_offset, string));
+        }
+        regions.sort_unstable();
+        regions.dedup();
+
+        // The offset of vn_file in each Elf_Verneed, which has the same layout for 32 and 64 bit,
+        // and the library it names
+        let mut version_files = Vec::new();
+        let mut verneed = dynamic.info.verneed as usize;
+        for _ in 0..dynamic.info.verneednum {
+            let file = read_u32(bytes, verneed + 4, elf.little_endian)?;
+            match elf.dynstrtab.get(file as usize) {
+                Some(Ok(string)) => version_files.push((verneed + 4, string.to_string())),
+                _ => bail!("a version need points outside of the string table"),
+            }
+            let next = read_u32(bytes, verneed + 12, elf.little_endian)? as usize;
+            if next == 0 {
+                break;
+            }
+            verneed += next;
+        }
+
+        // The linker may point other strings at the end of these, which must stay intact
+        for sym in elf.dynsyms.iter() {
+            let offset = strtab_offset + sym.st_name;
+            if regions
+                .iter()
+                .any(|(start, end)| *start < offset && offset < *end)
+            {
+                bail!("its RPATH shares space with the name of a symbol");
+            }
+        }
+        (
+            entry_size / 2,
+            elf.little_endian,
+            strtab_offset,
+            entries,
+            version_files,
+            regions,
+        )
+    };
+
+    if regions.is_empty() {
+        let needs_renames = entries
+            .iter()
+            .any(|(tag, _, string)| *tag == DT_NEEDED && renames.contains_key(string));
+        if !needs_renames {
+            return Ok(());
+        }
+        bail!(
+            "it has no RPATH or RUNPATH that could make room for the names of the bundled \
+             libraries"
+        );
+    }
+    for (start, end) in &regions {
+        for byte in &mut bytes[*start..*end] {
+            *byte = 0;
+        }
+    }
+    // Places a string in the first region with room for it, returning its string table index
+    let mut free = regions.clone();
+    let mut place = |bytes: &mut [u8], string: &str| -> Option<u64> {
+        let len = string.len() + 1;
+        let region = free.iter_mut().find(|(start, end)| end - start >= len)?;
+        let start = region.0;
+        bytes[start..start + string.len()].copy_from_slice(string.as_bytes());
+        region.0 += len;
+        Some((start - strtab_offset) as u64)
+    };
+
+    // The offset, value and size of the fields to change
+    let mut values = Vec::new();
+    let mut renamed = BTreeMap::new();
+    let runpath_index = place(bytes, runpath);
+    for (tag, value_offset, string) in &entries {
+        let index = match *tag {
+            DT_RPATH | DT_RUNPATH => runpath_index,
+            DT_NEEDED if renames.contains_key(string) => place(bytes, &renames[string]),
+            _ => continue,
+        };
+        match index {
+            Some(index) => {
+                if *tag == DT_NEEDED {
+                    renamed.insert(string.as_str(), index);
+                }
+                values.push((*value_offset, index, word_size));
+            }
+            None => {
+                bail!(
+                "its RPATH and RUNPATH are {} bytes, which is too short for the new RUNPATH and \
+                 names of the bundled libraries",
+                regions.iter().map(|(start, end)| end - start).sum::<usize>()
+            )
+            }
+        }
+    }
+    // The soname only keeps the linker from mistaking other copies for this one, so it's only
+    // changed if there's room
+    if let Some(soname) = soname {
+        for (tag, value_offset, _) in &entries {
+            if *tag == DT_SONAME {
+                if let Some(index) = place(bytes, soname) {
+                    values.push((*value_offset, index, word_size));
+                }
+            }
+        }
+    }
+    for (file_offset, file) in &version_files {
+        if let Some(index) = renamed.get(file.as_str()) {
+            values.push((*file_offset, *index, 4));
+        }
+    }
+
+    for (value_offset, value, size) in values {
+        let encoded = match (size, little_endian) {
+            (8, true) => value.to_le_bytes().to_vec(),
+            (8, false) => value.to_be_bytes().to_vec(),
+            (_, true) => (value as u32).to_le_bytes().to_vec(),
+            (_, false) => (value as u32).to_be_bytes().to_vec(),
+        };
+        bytes[value_offset..value_offset + encoded.len()].copy_from_slice(&encoded);
+    }
+    Ok(())
+}
+
+fn read_u32(bytes: &[u8], offset: usize, little_endian: bool) -> Result<u32, Error> {
+    let field: [u8; 4] = bytes
+        .get(offset..offset + 4)
+        .and_then(|field| field.try_into().ok())
+        .ok_or_else(|| format_err!("the version needs are truncated"))?;
+    if little_endian {
+        Ok(u32::from_le_bytes(field))
+    } else {
+        Ok(u32::from_be_bytes(field))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{compile_executable, compile_library, store_path};
+    use std::process::Command;
+    use tempfile::TempDir;
+
+    /// Returns the libraries the version needs of the ELF file refer to
+    fn version_files(bytes: &[u8]) -> Vec<String> {
+        let elf = Elf::parse(bytes).unwrap();
+        let dynamic = elf.dynamic.as_ref().unwrap();
+        let mut files = Vec::new();
+        let mut verneed = dynamic.info.verneed as usize;
+        for _ in 0..dynamic.info.verneednum {
+            let file = read_u32(bytes, verneed + 4, elf.little_endian).unwrap();
+            let file = elf.dynstrtab.get(file as usize).unwrap().unwrap();
+            files.push(file.to_string());
+            verneed += read_u32(bytes, verneed + 12, elf.little_endian).unwrap() as usize;
+        }
+        files
+    }
+
+    /// Builds a module that needs the versioned symbol of libver.so.1 from a store path, which
+    /// like every library in the store has a RUNPATH of its own
+    fn module_with_versioned_library(dir: &Path) -> PathBuf {
+        let lib_dir = dir.join(&store_path('v', "ver-1.0")[1..]).join("lib");
+        fs::create_dir_all(&lib_dir).unwrap();
+        let version_script = dir.join("ver.map");
+        fs::write(&version_script, "VER_1 { global: ver; local: *; };").unwrap();
+        let runpath = format!("-Wl,-rpath,{}", lib_dir.display());
+        compile_library(
+            &lib_dir,
+            "libver.so.1",
+            "int ver(void) { return 42; }",
+            &[
+                "-Wl,-soname,libver.so.1",
+                &format!("-Wl,--version-script,{}", version_script.display()),
+                &runpath,
+            ],
+        );
+        compile_library(
+            dir,
+            "libmodule.so",
+            "int ver(void);\nint module_ver(void) { return ver(); }",
+            &[
+                &format!("-L{}", lib_dir.display()),
+                "-l:libver.so.1",
+                &runpath,
+            ],
+        )
+    }
+
+    #[test]
+    fn test_renames_needed_libraries_and_their_version_needs() {
+        let dir = TempDir::new().unwrap();
+        let module = module_with_versioned_library(dir.path());
+        let out = dir.path().join("out");
+        let (artifact, bundled) =
+            bundle_libraries(&module, "$ORIGIN", Compatibility::Manylinux(2, 17), &out).unwrap();
+
+        assert_eq!(bundled.len(), 1);
+        let name = &bundled[0].name;
+        assert!(
+            name.starts_with("libver-") && name.ends_with(".so.1"),
+            "{}",
+            name
+        );
+        let bytes = fs::read(&artifact).unwrap();
+        let elf = Elf::parse(&bytes).unwrap();
+        assert!(elf.libraries.contains(&name.as_str()));
+        assert!(!elf.libraries.contains(&"libver.so.1"));
+        assert!(version_files(&bytes).contains(name));
+        assert!(!version_files(&bytes).contains(&"libver.so.1".to_string()));
+        let library = fs::read(&bundled[0].path).unwrap();
+        assert_eq!(Elf::parse(&library).unwrap().soname, Some(name.as_str()));
+    }
+
+    /// The dynamic linker finds the library of each version need among the loaded ones by name,
+    /// so a version need that still names the old library fails an assertion in glibc
+    #[test]
+    fn test_bundled_libraries_can_be_loaded() {
+        let dir = TempDir::new().unwrap();
+        let module = module_with_versioned_library(dir.path());
+        let out = dir.path().join("out");
+        let (artifact, _) =
+            bundle_libraries(&module, "$ORIGIN", Compatibility::Manylinux(2, 17), &out).unwrap();
+
+        let loader = compile_executable(
+            dir.path(),
+            "loader",
+            "#include <dlfcn.h>\n\
+             #include <stdio.h>\n\
+             int main(int argc, char **argv) {\n\
+                 void *module = dlopen(argv[1], RTLD_NOW);\n\
+                 if (!module) { fprintf(stderr, \"%s\\n\", dlerror()); return 1; }\n\
+                 int (*module_ver)(void) = (int (*)(void))dlsym(module, \"module_ver\");\n\
+                 return module_ver && module_ver() == 42 ? 0 : 2;\n\
+             }",
+            &["-ldl"],
+        );
+        // The original libver.so.1 must not be what's loaded
+        fs::remove_dir_all(dir.path().join(&store_path('v', "ver-1.0")[1..])).unwrap();
+        let output = Command::new(loader).arg(&artifact).output().unwrap();
+        assert!(
+            output.status.success(),
+            "{}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    /// The linker merges strings that are the end of another one, so a symbol named like the
+    /// last directory of the RPATH shares its string
+    #[test]
+    fn test_refuses_to_overwrite_an_rpath_shared_with_a_symbol() {
+        let dir = TempDir::new().unwrap();
+        let rpath = format!("-Wl,-rpath,{}/shared_tail", store_path('s', "shared-1.0"));
+        let module = compile_library(
+            dir.path(),
+            "libmodule.so",
+            "int shared_tail(void) { return 1; }",
+            &[&rpath],
+        );
+        let result = bundle_libraries(
+            &module,
+            "$ORIGIN",
+            Compatibility::Manylinux(2, 17),
+            &dir.path().join("out"),
+        );
+        let error = match result {
+            Ok(_) => panic!("bundled a module whose RPATH shares space with a symbol"),
+            Err(error) => error,
+        };
+        let message = error
+            .iter_chain()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(": ");
+        assert!(
+            message.contains("shares space with the name of a symbol"),
+            "{}",
+            message
+        );
+    }
+}
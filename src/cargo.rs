@@ -0,0 +1,393 @@
+//! Gets the artifact from cargo, for when `--artifact-path` isn't given: by looking it up in the
+//! target directory, by reading the messages of `cargo build --message-format json` or by
+//! running `cargo rustc` for `--cargo-build`
+
+use crate::platform::Platform;
+use cargo_metadata::MetadataCommand;
+use failure::{bail, Error, ResultExt};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// The parts of a compiler-artifact message of `cargo build --message-format=json` that tell
+/// which file was built for which target.
+///
+/// cargo_metadata's Message has all the fields, which makes it fail on the ones newer versions
+/// of cargo changed.
+#[derive(Deserialize)]
+struct ArtifactMessage {
+    package_id: String,
+    target: ArtifactTarget,
+    filenames: Vec<PathBuf>,
+    executable: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct ArtifactTarget {
+    kind: Vec<String>,
+}
+
+/// Asks cargo for the target directory of the crate, which takes `CARGO_TARGET_DIR`,
+/// `build.target-dir` in `.cargo/config` and workspaces into account
+pub fn target_dir(manifest_path: &Path) -> Result<PathBuf, Error> {
+    let metadata = MetadataCommand::new()
+        .manifest_path(manifest_path)
+        .no_deps()
+        .exec()
+        .context(format!(
+            "Failed to run cargo metadata for {}",
+            manifest_path.display()
+        ))?;
+    Ok(metadata.target_directory)
+}
+
+/// Returns the directory in the target directory that the profile builds into
+pub fn profile_dir(profile: &str) -> &str {
+    match profile {
+        "dev" | "test" => "debug",
+        "bench" => "release",
+        profile => profile,
+    }
+}
+
+/// Finds the artifact with the given file name that was built with the profile for the
+/// platform.
+///
+/// With a target triple, cargo puts it into `<target dir>/<triple>/<profile>`. Without one, it
+/// can be in `<target dir>/<profile>` or in the directory of any triple for this platform, and
+/// it's an error if more than one of those has it.
+pub fn find_artifact(
+    target_dir: &Path,
+    profile: &str,
+    triple: Option<&str>,
+    platform: &Platform,
+    file_name: &str,
+) -> Result<PathBuf, Error> {
+    let profile_dir = profile_dir(profile);
+    let mut dirs = Vec::new();
+    match triple {
+        Some(triple) => dirs.push(target_dir.join(triple).join(profile_dir)),
+        None => {
+            dirs.push(target_dir.join(profile_dir));
+            if let Ok(entries) = fs::read_dir(target_dir) {
+                let mut triples: Vec<String> = entries
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.file_name().to_string_lossy().to_string())
+                    .filter(|name| match Platform::from_triple(name) {
+                        Ok(other) => other.is_same_system(platform),
+                        Err(_) => false,
+                    })
+                    .collect();
+                triples.sort();
+                dirs.extend(
+                    triples
+                        .into_iter()
+                        .map(|triple| target_dir.join(triple).join(profile_dir)),
+                );
+            }
+        }
+    }
+
+    let candidates: Vec<PathBuf> = dirs
+        .iter()
+        .map(|dir| dir.join(file_name))
+        .filter(|path| path.is_file())
+        .collect();
+    match candidates.as_slice() {
+        [artifact] => Ok(artifact.clone()),
+        [] => bail!(
+            "Can't find {} in {}. Was it built with cargo build{}?",
+            file_name,
+            dirs.iter()
+                .map(|dir| dir.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            match profile {
+                "dev" => "".to_string(),
+                "release" => " --release".to_string(),
+                profile => format!(" --profile {}", profile),
+            }
+        ),
+        _ => bail!(
+            "Found more than one {}, pass --target or --artifact-path to pick one of:\n{}",
+            file_name,
+            candidates
+                .iter()
+                .map(|path| format!("    {}", path.display()))
+                .collect::<Vec<_>>()
+                .join("\n")
+        ),
+    }
+}
+
+/// Returns the package name of a package id, which is either "name version (source)" or, since
+/// cargo 1.77, a url like "path+file:///src/foo#bar@0.1.0" or "path+file:///src/foo#0.1.0"
+/// when the name is the last part of the path
+fn package_name(package_id: &str) -> &str {
+    match package_id.split_once('#') {
+        Some((_, fragment)) if fragment.contains('@') => fragment.split('@').next().unwrap(),
+        Some((url, _)) => url.rsplit('/').next().unwrap(),
+        None => package_id.split(' ').next().unwrap(),
+    }
+}
+
+/// Reads the messages of `cargo build --message-format=json` and returns the artifacts with the
+/// given file name that were built for a library or binary of the package.
+///
+/// Lines that aren't JSON, like those of `--message-format=json-render-diagnostics` builds mixed
+/// with other output, are skipped.
+pub fn artifacts_from_messages(
+    messages: impl BufRead,
+    package: &str,
+    is_bin: bool,
+    file_name: &str,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut artifacts = BTreeSet::new();
+    for line in messages.lines() {
+        let line = line.context("Failed to read the cargo messages")?;
+        let message: serde_json::Value = match serde_json::from_str(&line) {
+            Ok(message) => message,
+            Err(_) => continue,
+        };
+        if message.get("reason").and_then(|reason| reason.as_str()) != Some("compiler-artifact") {
+            continue;
+        }
+        let artifact: ArtifactMessage = serde_json::from_value(message)
+            .context(format!("Failed to parse the cargo message {}", line))?;
+        if package_name(&artifact.package_id) != package {
+            continue;
+        }
+
+        let kind = if is_bin { "bin" } else { "cdylib" };
+        if !artifact.target.kind.iter().any(|k| k == kind) {
+            continue;
+        }
+        let files = match artifact.executable {
+            Some(executable) if is_bin => vec![executable],
+            _ => artifact.filenames,
+        };
+        artifacts.extend(
+            files
+                .into_iter()
+                .filter(|file| matches!(file.file_name(), Some(name) if name == file_name)),
+        );
+    }
+    Ok(artifacts.into_iter().collect())
+}
+
+/// The features of the crate that cargo builds with
+pub struct Features<'a> {
+    pub features: &'a [String],
+    pub all_features: bool,
+    pub no_default_features: bool,
+}
+
+impl Features<'_> {
+    /// Adds `--features`, `--all-features` and `--no-default-features` to a cargo command
+    pub fn add_args(&self, command: &mut Command) {
+        if !self.features.is_empty() {
+            command.args(["--features", &self.features.join(",")]);
+        }
+        if self.all_features {
+            command.arg("--all-features");
+        }
+        if self.no_default_features {
+            command.arg("--no-default-features");
+        }
+    }
+}
+
+/// The options `--cargo-build` passes on to cargo
+pub struct CargoBuild<'a> {
+    pub manifest_path: &'a Path,
+    pub profile: &'a str,
+    pub target: Option<&'a str>,
+    pub features: Features<'a>,
+}
+
+/// Runs `cargo rustc` for the library as a cdylib, or for the binary with the given name, and
+/// returns the artifacts it reports like `artifacts_from_messages`.
+///
+/// On macOS, the library is linked with `-undefined dynamic_lookup`, since the symbols of
+/// libpython only come with the interpreter that loads it.
+pub fn cargo_build(
+    build: &CargoBuild,
+    platform: &Platform,
+    package: &str,
+    bin_name: Option<&str>,
+    file_name: &str,
+) -> Result<Vec<PathBuf>, Error> {
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let mut command = Command::new(cargo);
+    command
+        .arg("rustc")
+        .arg("--manifest-path")
+        .arg(build.manifest_path)
+        .args(["--message-format", "json-render-diagnostics"])
+        .args(["--profile", build.profile]);
+    if let Some(target) = build.target {
+        command.args(["--target", target]);
+    }
+    build.features.add_args(&mut command);
+    match bin_name {
+        Some(bin_name) => {
+            command.args(["--bin", bin_name]);
+        }
+        None => {
+            command.args(["--lib", "--crate-type", "cdylib"]);
+            if platform.is_macos() {
+                command.args([
+                    "--",
+                    "-C",
+                    "link-arg=-undefined",
+                    "-C",
+                    "link-arg=dynamic_lookup",
+                ]);
+            }
+        }
+    }
+
+    // The messages go to stdout, while cargo's progress and the diagnostics stay on stderr
+    let mut child = command
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Failed to run cargo")?;
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let artifacts = artifacts_from_messages(stdout, package, bin_name.is_some(), file_name)?;
+    let status = child.wait().context("Failed to wait for cargo")?;
+    if !status.success() {
+        bail!("cargo rustc failed with {}", status);
+    }
+    Ok(artifacts)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn artifact_message(package_id: &str, kind: &str, filenames: &[&str]) -> String {
+        serde_json::json!({
+            "reason": "compiler-artifact",
+            "package_id": package_id,
+            "target": { "kind": [kind], "name": "hello" },
+            "filenames": filenames,
+            "executable": null,
+            "fresh": false,
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_package_name_of_old_package_ids() {
+        assert_eq!(
+            package_name("hello-py 0.1.0 (path+file:///src/hello-py)"),
+            "hello-py"
+        );
+        assert_eq!(
+            package_name("pyo3 0.9.2 (registry+https://github.com/rust-lang/crates.io-index)"),
+            "pyo3"
+        );
+    }
+
+    #[test]
+    fn test_package_name_of_new_package_ids() {
+        assert_eq!(package_name("path+file:///src/hello-py#0.1.0"), "hello-py");
+        assert_eq!(
+            package_name("path+file:///src/crates/py#hello-py@0.1.0"),
+            "hello-py"
+        );
+        assert_eq!(
+            package_name("registry+https://github.com/rust-lang/crates.io-index#pyo3@0.9.2"),
+            "pyo3"
+        );
+    }
+
+    #[test]
+    fn test_artifacts_of_other_packages_are_skipped() {
+        let messages = [
+            "   Compiling hello-py v0.1.0".to_string(),
+            artifact_message(
+                "path+file:///src/world#0.1.0",
+                "cdylib",
+                &["/src/target/debug/libhello_py.so"],
+            ),
+            artifact_message(
+                "path+file:///src/hello-py#0.1.0",
+                "cdylib",
+                &[
+                    "/src/target/debug/libhello_py.so",
+                    "/src/target/debug/libhello_py.rlib",
+                ],
+            ),
+            artifact_message(
+                "path+file:///src/hello-py#0.1.0",
+                "lib",
+                &["/src/target/debug/libhello_py.rlib"],
+            ),
+        ]
+        .join("\n");
+        let artifacts =
+            artifacts_from_messages(messages.as_bytes(), "hello-py", false, "libhello_py.so")
+                .unwrap();
+        assert_eq!(
+            artifacts,
+            vec![PathBuf::from("/src/target/debug/libhello_py.so")]
+        );
+    }
+
+    #[test]
+    fn test_more_than_one_cdylib() {
+        // e.g. when the same package was built for two targets in one invocation
+        let messages = [
+            artifact_message(
+                "hello-py 0.1.0 (path+file:///src/hello-py)",
+                "cdylib",
+                &["/src/target/x86_64-unknown-linux-gnu/debug/libhello_py.so"],
+            ),
+            artifact_message(
+                "hello-py 0.1.0 (path+file:///src/hello-py)",
+                "cdylib",
+                &["/src/target/aarch64-unknown-linux-gnu/debug/libhello_py.so"],
+            ),
+            // Reported again when fresh
+            artifact_message(
+                "hello-py 0.1.0 (path+file:///src/hello-py)",
+                "cdylib",
+                &["/src/target/aarch64-unknown-linux-gnu/debug/libhello_py.so"],
+            ),
+        ]
+        .join("\n");
+        let artifacts =
+            artifacts_from_messages(messages.as_bytes(), "hello-py", false, "libhello_py.so")
+                .unwrap();
+        assert_eq!(
+            artifacts,
+            vec![
+                PathBuf::from("/src/target/aarch64-unknown-linux-gnu/debug/libhello_py.so"),
+                PathBuf::from("/src/target/x86_64-unknown-linux-gnu/debug/libhello_py.so"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_binaries_come_from_the_executable() {
+        let message = serde_json::json!({
+            "reason": "compiler-artifact",
+            "package_id": "path+file:///src/hello#0.1.0",
+            "target": { "kind": ["bin"], "name": "hello" },
+            "filenames": ["/src/target/debug/hello", "/src/target/debug/hello.dwp"],
+            "executable": "/src/target/debug/hello",
+        })
+        .to_string();
+        let artifacts =
+            artifacts_from_messages(message.as_bytes(), "hello", true, "hello").unwrap();
+        assert_eq!(artifacts, vec![PathBuf::from("/src/target/debug/hello")]);
+        let artifacts =
+            artifacts_from_messages(message.as_bytes(), "hello", false, "hello").unwrap();
+        assert!(artifacts.is_empty());
+    }
+}
@@ -0,0 +1,215 @@
+//! The .data directory of the wheel as specified in PEP 427, whose subdirectories an installer
+//! moves to the install paths of the same names
+
+use failure::{bail, Error, ResultExt};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// The install schemes a wheel can put files into
+pub const SCHEMES: &[&str] = &["scripts", "headers", "data", "purelib", "platlib"];
+
+/// Maps the scheme to the files in it, which map the path inside the scheme's directory to the
+/// file to copy there.
+pub type DataFiles = BTreeMap<String, BTreeMap<PathBuf, PathBuf>>;
+
+/// A file or directory passed on the command line for one of the schemes
+#[derive(Debug)]
+pub struct DataArg {
+    scheme: String,
+    path: PathBuf,
+}
+
+/// Parses `scheme=path`, e.g. `headers=include`
+pub fn parse_data_arg(arg: &str) -> Result<DataArg, String> {
+    let mut parts = arg.splitn(2, '=');
+    let scheme = parts.next().unwrap();
+    let path = match parts.next() {
+        Some(path) if !path.is_empty() => path,
+        _ => return Err(format!("expected scheme=path, got {}", arg)),
+    };
+    if !SCHEMES.contains(&scheme) {
+        return Err(format!(
+            "unknown scheme {}, expected one of {}",
+            scheme,
+            SCHEMES.join(", ")
+        ));
+    }
+    Ok(DataArg {
+        scheme: scheme.to_string(),
+        path: PathBuf::from(path),
+    })
+}
+
+/// Collects the files of a directory with a subdirectory for each scheme, like the .data
+/// directory itself
+pub fn from_data_dir(data_dir: &Path) -> Result<DataFiles, Error> {
+    let mut data_files = DataFiles::new();
+    let entries = fs::read_dir(data_dir).context(format!("Can't read {}", data_dir.display()))?;
+    for entry in entries {
+        let entry = entry.context(format!("Can't read {}", data_dir.display()))?;
+        let scheme = entry.file_name().to_string_lossy().to_string();
+        if !SCHEMES.contains(&scheme.as_str()) || !entry.path().is_dir() {
+            bail!(
+                "{} must only contain directories named after the schemes {}, but has {}",
+                data_dir.display(),
+                SCHEMES.join(", "),
+                scheme
+            );
+        }
+        add_path(&mut data_files, &scheme, &entry.path(), true)?;
+    }
+    Ok(data_files)
+}
+
+/// Adds the files and directories given on the command line
+pub fn add_data_args(data_files: &mut DataFiles, args: &[DataArg]) -> Result<(), Error> {
+    for arg in args {
+        add_path(data_files, &arg.scheme, &arg.path, false)?;
+    }
+    Ok(())
+}
+
+/// Adds a file under its file name or the contents of a directory to the scheme. With
+/// `contents_only`, even a file is expected to be a directory.
+fn add_path(
+    data_files: &mut DataFiles,
+    scheme: &str,
+    path: &Path,
+    contents_only: bool,
+) -> Result<(), Error> {
+    let files = data_files.entry(scheme.to_string()).or_default();
+    let mut add = |target: PathBuf, source: PathBuf| -> Result<(), Error> {
+        if let Some(existing) = files.get(&target) {
+            bail!(
+                "Both {} and {} would be {}/{} in the wheel",
+                existing.display(),
+                source.display(),
+                scheme,
+                target.display()
+            );
+        }
+        files.insert(target, source);
+        Ok(())
+    };
+
+    if path.is_file() && !contents_only {
+        let file_name = path.file_name().unwrap();
+        add(PathBuf::from(file_name), path.to_path_buf())?;
+    } else if path.is_dir() {
+        let walk = WalkDir::new(path)
+            .follow_links(true)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()));
+        for entry in walk {
+            let entry = entry.context(format!("Failed to list {}", path.display()))?;
+            if entry.file_type().is_dir() {
+                continue;
+            }
+            let relative = entry.path().strip_prefix(path)?.to_path_buf();
+            add(relative, entry.path().to_path_buf())?;
+        }
+    } else {
+        bail!("{} for {} doesn't exist", path.display(), scheme);
+    }
+    Ok(())
+}
+
+/// Replaces a shebang that runs python, e.g. `#!/usr/bin/env python3`, with `#!python`, which
+/// installers rewrite to the interpreter the wheel is installed for. Other scripts and binaries
+/// are returned as they are.
+pub fn rewrite_shebang(script: &[u8]) -> Vec<u8> {
+    if !script.starts_with(b"#!") {
+        return script.to_vec();
+    }
+    let line_end = script
+        .iter()
+        .position(|byte| *byte == b'\n')
+        .unwrap_or(script.len());
+    let line = String::from_utf8_lossy(&script[2..line_end]);
+    let mut words = line.split_whitespace();
+    let mut interpreter = words.next().unwrap_or_default();
+    if base_name(interpreter) == "env" {
+        interpreter = words
+            .find(|word| !word.starts_with('-') && !word.contains('='))
+            .unwrap_or_default();
+    }
+    if !base_name(interpreter).starts_with("python") {
+        return script.to_vec();
+    }
+
+    // Keeps a \r\n line ending as it is
+    let line_end = if script[..line_end].ends_with(b"\r") {
+        line_end - 1
+    } else {
+        line_end
+    };
+    let mut rewritten = b"#!python".to_vec();
+    rewritten.extend_from_slice(&script[line_end..]);
+    rewritten
+}
+
+fn base_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rewritten(script: &str) -> String {
+        String::from_utf8(rewrite_shebang(script.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn test_nix_store_python() {
+        let script = format!(
+            "#!{}/bin/python3\nprint('hello')\n",
+            crate::test_utils::store_path('a', "python3-3.9.18")
+        );
+        assert_eq!(rewritten(&script), "#!python\nprint('hello')\n");
+    }
+
+    #[test]
+    fn test_env_python() {
+        assert_eq!(
+            rewritten("#!/usr/bin/env python\nprint('hello')\n"),
+            "#!python\nprint('hello')\n"
+        );
+        assert_eq!(
+            rewritten("#!/usr/bin/env -S PYTHONUNBUFFERED=1 python3.9 -u\nprint('hello')\n"),
+            "#!python\nprint('hello')\n"
+        );
+    }
+
+    #[test]
+    fn test_other_interpreters_are_kept() {
+        let script = "#!/bin/sh\necho hello\n";
+        assert_eq!(rewritten(script), script);
+        let script = "#!/usr/bin/env bash\necho hello\n";
+        assert_eq!(rewritten(script), script);
+    }
+
+    #[test]
+    fn test_no_shebang() {
+        let script = "print('hello')\n";
+        assert_eq!(rewritten(script), script);
+        assert_eq!(rewritten(""), "");
+        let binary = b"\x7fELF\x02\x01\x01\x00";
+        assert_eq!(rewrite_shebang(binary), binary.to_vec());
+    }
+
+    #[test]
+    fn test_crlf_line_endings() {
+        assert_eq!(
+            rewritten("#!/usr/bin/env python3\r\nprint('hello')\r\n"),
+            "#!python\r\nprint('hello')\r\n"
+        );
+        assert_eq!(rewritten("#!/usr/bin/python3\r\n"), "#!python\r\n");
+    }
+
+    #[test]
+    fn test_shebang_without_newline() {
+        assert_eq!(rewritten("#!/usr/bin/python3"), "#!python");
+    }
+}
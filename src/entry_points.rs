@@ -0,0 +1,278 @@
+//! Entry points as specified in https://packaging.python.org/specifications/entry-points/
+
+use failure::{bail, Error};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Maps the group, e.g. `console_scripts`, to the entry points in it, which map a name to an
+/// object reference like `module:function`.
+pub type EntryPoints = BTreeMap<String, BTreeMap<String, String>>;
+
+/// The group of the entry points that become executables
+pub const CONSOLE_SCRIPTS: &str = "console_scripts";
+
+/// An entry point passed on the command line
+#[derive(Debug)]
+pub struct EntryPointArg {
+    group: String,
+    name: String,
+    value: String,
+}
+
+/// Parses `name=module:func` into a console script or `group:name=module:func` into an entry
+/// point of the given group.
+pub fn parse_entry_point(arg: &str) -> Result<EntryPointArg, String> {
+    let mut parts = arg.splitn(2, '=');
+    let key = parts.next().unwrap().trim();
+    let value = match parts.next() {
+        Some(value) if !value.trim().is_empty() => value.trim(),
+        _ => return Err(format!("expected name=module:func, got {}", arg)),
+    };
+
+    let (group, name) = match key.find(':') {
+        Some(i) => (&key[..i], &key[i + 1..]),
+        None => (CONSOLE_SCRIPTS, key),
+    };
+    if group.is_empty() || name.is_empty() {
+        return Err(format!("expected [group:]name=module:func, got {}", arg));
+    }
+
+    Ok(EntryPointArg {
+        group: group.to_string(),
+        name: name.to_string(),
+        value: value.to_string(),
+    })
+}
+
+impl fmt::Display for EntryPointArg {
+    /// Formats the entry point the way it's given on the command line
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}={}", self.group, self.name, self.value)
+    }
+}
+
+/// Adds the entry points given on the command line, replacing ones with the same name
+pub fn add_entry_point_args(entry_points: &mut EntryPoints, args: &[EntryPointArg]) {
+    for arg in args {
+        entry_points
+            .entry(arg.group.clone())
+            .or_default()
+            .insert(arg.name.clone(), arg.value.clone());
+    }
+}
+
+/// Reads `[project.scripts]`, `[project.gui-scripts]` and `[project.entry-points.<group>]` from
+/// pyproject.toml, if there is one.
+pub fn from_pyproject_toml(pyproject: Option<&toml::Value>) -> Result<EntryPoints, Error> {
+    let mut entry_points = EntryPoints::new();
+    let project = match pyproject.and_then(|pyproject| pyproject.get("project")) {
+        Some(project) => project,
+        None => return Ok(entry_points),
+    };
+
+    if let Some(scripts) = project.get("scripts") {
+        entry_points.insert(
+            CONSOLE_SCRIPTS.to_string(),
+            string_table(scripts, "scripts")?,
+        );
+    }
+    if let Some(gui_scripts) = project.get("gui-scripts") {
+        entry_points.insert(
+            "gui_scripts".to_string(),
+            string_table(gui_scripts, "gui-scripts")?,
+        );
+    }
+    if let Some(groups) = project.get("entry-points") {
+        let groups = match groups.as_table() {
+            Some(groups) => groups,
+            None => bail!("[project.entry-points] in pyproject.toml must be a table"),
+        };
+        for (group, table) in groups {
+            // PEP 621 reserves these two for the dedicated tables
+            let dedicated = match group.as_str() {
+                CONSOLE_SCRIPTS => Some("scripts"),
+                "gui_scripts" => Some("gui-scripts"),
+                _ => None,
+            };
+            if let Some(dedicated) = dedicated {
+                bail!(
+                    "[project.entry-points.{}] in pyproject.toml must be given as [project.{}] instead",
+                    group,
+                    dedicated
+                );
+            }
+            let key = format!("entry-points.{}", group);
+            entry_points.insert(group.clone(), string_table(table, &key)?);
+        }
+    }
+
+    Ok(entry_points)
+}
+
+/// Converts a table of strings, reporting the offending key in `[project.<key>]` otherwise
+fn string_table(value: &toml::Value, key: &str) -> Result<BTreeMap<String, String>, Error> {
+    let table = match value.as_table() {
+        Some(table) => table,
+        None => bail!("[project.{}] in pyproject.toml must be a table", key),
+    };
+
+    let mut strings = BTreeMap::new();
+    for (name, value) in table {
+        match value.as_str() {
+            Some(value) => strings.insert(name.clone(), value.to_string()),
+            None => bail!(
+                "{} in [project.{}] in pyproject.toml must be a string",
+                name,
+                key
+            ),
+        };
+    }
+    Ok(strings)
+}
+
+/// Formats the entry points in the ini-like format of entry_points.txt, sorted so that it comes
+/// out the same every time
+pub fn entry_points_txt(entry_points: &EntryPoints) -> String {
+    let mut text = String::new();
+    for (group, entries) in entry_points {
+        if entries.is_empty() {
+            continue;
+        }
+        if !text.is_empty() {
+            text += "\n";
+        }
+        text += &format!("[{}]\n", group);
+        for (name, value) in entries {
+            text += &format!("{} = {}\n", name, value);
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(arg: &str) -> (String, String, String) {
+        let arg = parse_entry_point(arg).unwrap();
+        (arg.group, arg.name, arg.value)
+    }
+
+    fn parse_pyproject(contents: &str) -> toml::Value {
+        toml::from_str(contents).unwrap()
+    }
+
+    #[test]
+    fn test_console_script() {
+        assert_eq!(
+            parse("hello = hello.cli:main"),
+            (
+                CONSOLE_SCRIPTS.to_string(),
+                "hello".to_string(),
+                "hello.cli:main".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_entry_point_with_group() {
+        assert_eq!(
+            parse("pytest11:hello=hello.plugin:setup"),
+            (
+                "pytest11".to_string(),
+                "hello".to_string(),
+                "hello.plugin:setup".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_missing_equals_sign() {
+        assert!(parse_entry_point("hello").is_err());
+        assert!(parse_entry_point("pytest11:hello").is_err());
+    }
+
+    #[test]
+    fn test_empty_parts() {
+        assert!(parse_entry_point("hello=").is_err());
+        assert!(parse_entry_point("=hello:main").is_err());
+        assert!(parse_entry_point(":hello=hello:main").is_err());
+        assert!(parse_entry_point("pytest11:=hello:main").is_err());
+    }
+
+    #[test]
+    fn test_dedicated_groups_are_rejected_in_entry_points() {
+        let pyproject = parse_pyproject(
+            r#"
+            [project.entry-points.console_scripts]
+            hello = "hello:main"
+            "#,
+        );
+        assert!(from_pyproject_toml(Some(&pyproject)).is_err());
+
+        let pyproject = parse_pyproject(
+            r#"
+            [project.entry-points.gui_scripts]
+            hello = "hello:main"
+            "#,
+        );
+        assert!(from_pyproject_toml(Some(&pyproject)).is_err());
+    }
+
+    #[test]
+    fn test_entry_points_from_pyproject_toml() {
+        let pyproject = parse_pyproject(
+            r#"
+            [project.scripts]
+            hello = "hello:main"
+
+            [project.gui-scripts]
+            hello-gui = "hello:gui"
+
+            [project.entry-points.pytest11]
+            hello = "hello.plugin"
+            "#,
+        );
+        let entry_points = from_pyproject_toml(Some(&pyproject)).unwrap();
+        assert_eq!(entry_points[CONSOLE_SCRIPTS]["hello"], "hello:main");
+        assert_eq!(entry_points["gui_scripts"]["hello-gui"], "hello:gui");
+        assert_eq!(entry_points["pytest11"]["hello"], "hello.plugin");
+        assert!(from_pyproject_toml(None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_entry_points_txt_is_sorted() {
+        let mut entry_points = EntryPoints::new();
+        let args = [
+            parse_entry_point("pytest11:plugin=hello.plugin").unwrap(),
+            parse_entry_point("world=hello:world").unwrap(),
+            parse_entry_point("hello=hello:main").unwrap(),
+        ];
+        add_entry_point_args(&mut entry_points, &args);
+        entry_points.insert("empty".to_string(), BTreeMap::new());
+
+        assert_eq!(
+            entry_points_txt(&entry_points),
+            "[console_scripts]\n\
+             hello = hello:main\n\
+             world = hello:world\n\
+             \n\
+             [pytest11]\n\
+             plugin = hello.plugin\n"
+        );
+    }
+
+    #[test]
+    fn test_arguments_replace_entry_points_of_the_same_name() {
+        let mut entry_points = EntryPoints::new();
+        add_entry_point_args(
+            &mut entry_points,
+            &[parse_entry_point("hello=hello:old").unwrap()],
+        );
+        add_entry_point_args(
+            &mut entry_points,
+            &[parse_entry_point("hello=hello:new").unwrap()],
+        );
+        assert_eq!(entry_points[CONSOLE_SCRIPTS]["hello"], "hello:new");
+    }
+}
@@ -0,0 +1,126 @@
+use std::fmt;
+
+/// Exit code for invalid or conflicting command line arguments, which is also what clap uses,
+/// including a wheel to verify that can't be read or opened as a zip archive
+pub const EXIT_USAGE: i32 = 1;
+/// Exit code for a Cargo.toml or pyproject.toml that can't be read or understood
+pub const EXIT_MANIFEST: i32 = 2;
+/// Exit code for metadata that can't be derived from the manifest, e.g. a missing readme
+pub const EXIT_METADATA: i32 = 3;
+/// Exit code for python interpreters that can't be found, introspected or are ambiguous
+pub const EXIT_INTERPRETER: i32 = 4;
+/// Exit code for an artifact that can't be read or isn't what it should be
+pub const EXIT_ARTIFACT: i32 = 5;
+/// Exit code for failures while writing the wheel or source distribution
+pub const EXIT_WHEEL: i32 = 6;
+/// Exit code for a wheel that `verify` found problems in
+pub const EXIT_VERIFY: i32 = 7;
+
+/// Describes the exit codes for the help text. Panics exit with rust's 101 and are always bugs in
+/// maturin-nix.
+pub const EXIT_CODES_HELP: &str = "EXIT CODES:
+    1    Invalid command line arguments, or a wheel to verify that can't be opened
+    2    Cargo.toml or pyproject.toml can't be read or understood
+    3    Python metadata can't be derived from the manifest
+    4    Python interpreters can't be found or introspected
+    5    The artifact can't be read or is invalid
+    6    Writing the wheel or sdist failed
+    7    The wheel failed verification
+    101  Internal error, please report a bug";
+
+/// The failures of maturin-nix, categorized by what the user has to look at to fix them.
+///
+/// Each variant keeps the whole cause chain of the underlying error, so that the report can say
+/// both what we were doing and why it failed.
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid or conflicting command line arguments, or a file they name that can't be read
+    Usage(failure::Error),
+    /// A Cargo.toml or pyproject.toml that can't be read or understood
+    Manifest(failure::Error),
+    /// Metadata that can't be derived from the manifest
+    Metadata(failure::Error),
+    /// Python interpreters that can't be found, introspected or are ambiguous
+    Interpreter(failure::Error),
+    /// An artifact that can't be read or isn't what it should be
+    Artifact(failure::Error),
+    /// Failures while writing the wheel or source distribution
+    Wheel(failure::Error),
+    /// Problems found in an existing wheel
+    Verify(failure::Error),
+}
+
+impl Error {
+    pub fn usage(err: impl Into<failure::Error>) -> Self {
+        Error::Usage(err.into())
+    }
+
+    pub fn manifest(err: impl Into<failure::Error>) -> Self {
+        Error::Manifest(err.into())
+    }
+
+    pub fn metadata(err: impl Into<failure::Error>) -> Self {
+        Error::Metadata(err.into())
+    }
+
+    pub fn interpreter(err: impl Into<failure::Error>) -> Self {
+        Error::Interpreter(err.into())
+    }
+
+    pub fn artifact(err: impl Into<failure::Error>) -> Self {
+        Error::Artifact(err.into())
+    }
+
+    pub fn wheel(err: impl Into<failure::Error>) -> Self {
+        Error::Wheel(err.into())
+    }
+
+    pub fn verify(err: impl Into<failure::Error>) -> Self {
+        Error::Verify(err.into())
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Usage(_) => EXIT_USAGE,
+            Error::Manifest(_) => EXIT_MANIFEST,
+            Error::Metadata(_) => EXIT_METADATA,
+            Error::Interpreter(_) => EXIT_INTERPRETER,
+            Error::Artifact(_) => EXIT_ARTIFACT,
+            Error::Wheel(_) => EXIT_WHEEL,
+            Error::Verify(_) => EXIT_VERIFY,
+        }
+    }
+
+    fn cause(&self) -> &failure::Error {
+        match self {
+            Error::Usage(err)
+            | Error::Manifest(err)
+            | Error::Metadata(err)
+            | Error::Interpreter(err)
+            | Error::Artifact(err)
+            | Error::Wheel(err)
+            | Error::Verify(err) => err,
+        }
+    }
+
+    /// Prints the error with its causes in the same style as clap's errors
+    pub fn report(&self) {
+        let err = ansi_term::Color::Red.bold().paint("error:");
+        eprintln!("{} {}", err, self);
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut chain = self.cause().iter_chain();
+        if let Some(first) = chain.next() {
+            write!(f, "{}", first)?;
+        }
+        for cause in chain {
+            write!(f, "\n  caused by: {}", cause)?;
+        }
+        Ok(())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,65 @@
+//! License files in the .dist-info directory as specified in PEP 639 and core metadata 2.4
+
+use failure::{bail, Error, ResultExt};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Returns the license files of the crate, mapping their path inside `.dist-info/licenses` to
+/// the file.
+///
+/// That's the given license file, i.e. the `license-file` of Cargo.toml or the `license.file`
+/// of pyproject.toml, if there is one, and otherwise the files next to the manifest
+/// whose names start with "license", "licence" or "copying" in any case, like "LICENSE",
+/// "LICENSE-APACHE" or "license-mit". The path is the one relative to the manifest directory,
+/// which PEP 639 asks to keep, or only the file name for a license file outside of it.
+pub fn find_license_files(
+    manifest_dir: &Path,
+    license_file: Option<&str>,
+) -> Result<Vec<(String, PathBuf)>, Error> {
+    if let Some(license_file) = license_file {
+        let license_file = Path::new(license_file);
+        let path = manifest_dir.join(license_file);
+        if !path.is_file() {
+            bail!("The license-file {} doesn't exist", path.display());
+        }
+        let is_inside = license_file
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        let name = if is_inside {
+            license_file
+                .components()
+                .filter(|component| *component != Component::CurDir)
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/")
+        } else {
+            license_file
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_string()
+        };
+        return Ok(vec![(name, path)]);
+    }
+
+    // The manifest path may be relative without a directory, i.e. "Cargo.toml"
+    let dir = if manifest_dir == Path::new("") {
+        Path::new(".")
+    } else {
+        manifest_dir
+    };
+    let mut license_files = Vec::new();
+    for entry in fs::read_dir(dir).context(format!("Can't read {}", dir.display()))? {
+        let entry = entry.context(format!("Can't read {}", dir.display()))?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let lowercase = name.to_lowercase();
+        let is_license = ["license", "licence", "copying"]
+            .iter()
+            .any(|prefix| lowercase.starts_with(prefix));
+        if is_license && entry.path().is_file() {
+            license_files.push((name, manifest_dir.join(entry.file_name())));
+        }
+    }
+    license_files.sort();
+    Ok(license_files)
+}
@@ -1,43 +1,771 @@
+use failure::{format_err, ResultExt};
 use maturin::*;
-use std::path::PathBuf;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
 use std::process;
 use structopt::clap::AppSettings;
 use structopt::StructOpt;
 
+mod artifact;
+mod bundle;
+mod cargo;
+mod data;
+mod entry_points;
+mod error;
+mod licenses;
+mod module_writer;
+mod nix_expr;
+mod platform;
+mod pyproject;
+mod sdist;
+mod store_paths;
+mod stubs;
+#[cfg(test)]
+mod test_utils;
+mod third_party;
+mod verify;
+mod wheel_writer;
+
+use data::DataFiles;
+use entry_points::EntryPoints;
+use error::{Error, Result};
+use platform::{Compatibility, Machine, Platform};
+
 /// Build python wheels
 #[derive(Debug, StructOpt)]
 struct Info {
     /// The name of the python module to create. This module name must match that of the library in
-    /// the wheel or the wheel will fail when trying to import.
+    /// the wheel or the wheel will fail when trying to import. A dotted name like
+    /// "mypkg._native" places the native module inside the "mypkg" package.
     #[structopt(long = "module-name")]
     module_name: String,
 
     /// Path to the Cargo.toml file. This file is used to provide the metadata for the python
     /// wheel. Be aware that if this points to readme file, that readme file should also be in the
-    /// same folder.
+    /// same folder. The fields of a `[project]` table in the pyproject.toml next to it replace
+    /// the ones from Cargo.toml.
     #[structopt(long = "manifest-path")]
     manifest_path: PathBuf,
+
+    /// Which kind of bindings the crate uses. When omitted, pyo3 or rust-cpython bindings are
+    /// detected from the dependencies in the manifest, falling back to cffi for a cdylib and bin
+    /// otherwise.
+    #[structopt(long, possible_values = &["pyo3", "rust-cpython", "cffi", "bin"])]
+    bindings: Option<String>,
+
+    /// Build a single stable ABI wheel that works with every python version starting at the
+    /// given one, e.g. "3.7". The artifact must be compiled against the limited API. No python
+    /// interpreters are needed in this mode.
+    #[structopt(long, value_name = "min-version", parse(try_from_str = parse_python_version))]
+    abi3: Option<(usize, usize)>,
+
+    /// The python interpreter to build for. Can be given multiple times; when omitted the
+    /// interpreters are searched for on PATH.
+    #[structopt(long = "interpreter", value_name = "path")]
+    interpreters: Vec<PathBuf>,
+
+    /// Only build for the given python version, e.g. "3.7". Can be given multiple times.
+    #[structopt(
+        long = "python-version",
+        value_name = "version",
+        parse(try_from_str = parse_python_version)
+    )]
+    python_versions: Vec<(usize, usize)>,
+
+    /// Adds an entry point to the wheel, either a console script as `name=module:func` or one
+    /// in any other group as `group:name=module:func`, e.g. `gui_scripts:app=app:main`. Takes
+    /// precedence over the `[package.metadata.maturin.scripts]` in Cargo.toml and the
+    /// `[project.scripts]`, `[project.gui-scripts]` and `[project.entry-points]` in the
+    /// pyproject.toml next to it. Can be given multiple times.
+    #[structopt(
+        long = "entry-point",
+        value_name = "entry-point",
+        parse(try_from_str = entry_points::parse_entry_point)
+    )]
+    entry_points: Vec<entry_points::EntryPointArg>,
+
+    /// The rust target triple the artifact was compiled for, e.g. "aarch64-unknown-linux-gnu".
+    /// Decides the platform tag and the suffix of the native module. Python interpreters still
+    /// run on this machine and only provide the python version and ABI. Defaults to the
+    /// platform maturin-nix runs on.
+    #[structopt(long, value_name = "triple")]
+    target: Option<String>,
+
+    /// Tags the linux wheel as manylinux_X_Y or musllinux_X_Y, which pip installs from indexes,
+    /// or as linux. The artifact is checked against the promise: it must be linked against the
+    /// right libc and, for manylinux, need no symbols newer than glibc X.Y and the libstdc++ and
+    /// libgcc_s of its manylinux policy. Other libraries must be bundled with --bundle-libs.
+    #[structopt(
+        long,
+        value_name = "manylinux_X_Y|musllinux_X_Y|linux",
+        parse(try_from_str = platform::parse_compatibility)
+    )]
+    compatibility: Option<Compatibility>,
+
+    /// Replaces the platform part of the wheel tags, e.g. "manylinux2014_x86_64", without any
+    /// checks. Compressed tags like "manylinux_2_17_x86_64.manylinux2014_x86_64" are expanded
+    /// in the WHEEL file.
+    #[structopt(
+        long = "platform-tag",
+        value_name = "tag",
+        conflicts_with = "compatibility",
+        parse(try_from_str = parse_platform_tag)
+    )]
+    platform_tag: Option<String>,
+
+    /// Replaces the version from Cargo.toml and pyproject.toml, e.g. for a "1.0.0.post1" rebuild
+    /// or a local version label like "1.0.0+nix".
+    #[structopt(long = "set-version", value_name = "version", parse(try_from_str = parse_version))]
+    version: Option<String>,
+
+    /// Replaces the summary, i.e. the description in Cargo.toml or pyproject.toml.
+    #[structopt(long, value_name = "summary")]
+    summary: Option<String>,
+
+    /// Adds a requirement like "numpy>=1.16", replacing any requirement on the same
+    /// distribution from pyproject.toml. Can be given multiple times.
+    #[structopt(long = "requires-dist", value_name = "requirement")]
+    requires_dist: Vec<String>,
+
+    /// Replaces the python versions the wheel supports, e.g. ">=3.7".
+    #[structopt(long = "requires-python", value_name = "specifier")]
+    requires_python: Option<String>,
+
+    /// Adds a trove classifier. Can be given multiple times.
+    #[structopt(long = "classifier", value_name = "classifier")]
+    classifiers: Vec<String>,
+
+    /// Adds a project url as "label, url", replacing a url with the same label from
+    /// pyproject.toml. Can be given multiple times.
+    #[structopt(
+        long = "project-url",
+        value_name = "label, url",
+        parse(try_from_str = parse_project_url)
+    )]
+    project_urls: Vec<String>,
+}
+
+/// Cargo.toml and the pyproject.toml next to it, read once per run so that everything taken from
+/// them comes from the same contents
+struct Manifests {
+    /// Cargo.toml as parsed by maturin
+    cargo_toml: CargoToml,
+    /// Cargo.toml as plain toml, for the parts maturin's CargoToml doesn't expose
+    manifest: toml::Value,
+    /// pyproject.toml, if there is one
+    pyproject: Option<toml::Value>,
 }
 
 impl Info {
-    fn meta21(&self) -> Metadata21 {
-        let cargo_toml = CargoToml::from_path(&self.manifest_path).expect("manifest_file");
+    fn platform(&self) -> Result<Platform> {
+        let mut platform = match &self.target {
+            Some(triple) => Platform::from_triple(triple).map_err(Error::usage)?,
+            None => Platform::current().map_err(Error::usage)?,
+        };
+        if let Some(compatibility) = self.compatibility {
+            platform = platform
+                .with_compatibility(compatibility)
+                .map_err(Error::usage)?;
+        }
+        if let Some(platform_tag) = &self.platform_tag {
+            platform = platform.with_platform_tag(platform_tag.clone());
+        }
+        Ok(platform)
+    }
 
+    /// Reads Cargo.toml and the pyproject.toml next to it
+    fn manifests(&self) -> Result<Manifests> {
+        let contents = fs::read_to_string(&self.manifest_path)
+            .context(format!("Can't read {}", self.manifest_path.display()))
+            .map_err(Error::manifest)?;
+        let cargo_toml = toml::from_str(&contents)
+            .context(format!("Failed to parse {}", self.manifest_path.display()))
+            .map_err(Error::manifest)?;
+        let manifest = toml::from_str(&contents)
+            .context(format!("Failed to parse {}", self.manifest_path.display()))
+            .map_err(Error::manifest)?;
+
+        let manifest_dir = self.manifest_path.parent().unwrap();
+        let pyproject = pyproject::read(manifest_dir).map_err(Error::manifest)?;
+        Ok(Manifests {
+            cargo_toml,
+            manifest,
+            pyproject,
+        })
+    }
+
+    fn meta21(&self, manifests: &Manifests) -> Result<Metadata21> {
         // The manifest directory is only used when the target toml file points to a readme.
         let manifest_dir = self.manifest_path.parent().unwrap();
 
-        Metadata21::from_cargo_toml(&cargo_toml, &manifest_dir).expect("metadata21")
+        let mut metadata21 = Metadata21::from_cargo_toml(&manifests.cargo_toml, manifest_dir)
+            .map_err(Error::metadata)?;
+        pyproject::apply_project_table(&mut metadata21, manifests.pyproject.as_ref(), manifest_dir)
+            .map_err(Error::metadata)?;
+        self.apply_overrides(&mut metadata21);
+        Ok(metadata21)
+    }
+
+    /// Applies the metadata given on the command line, which takes precedence over both
+    /// Cargo.toml and pyproject.toml
+    fn apply_overrides(&self, metadata21: &mut Metadata21) {
+        if let Some(version) = &self.version {
+            metadata21.version = version.clone();
+        }
+        if let Some(summary) = &self.summary {
+            metadata21.summary = Some(summary.clone());
+        }
+        if let Some(requires_python) = &self.requires_python {
+            metadata21.requires_python = Some(requires_python.clone());
+        }
+        for requirement in &self.requires_dist {
+            let name = requirement_name(requirement);
+            metadata21
+                .requires_dist
+                .retain(|existing| requirement_name(existing) != name);
+            metadata21.requires_dist.push(requirement.clone());
+        }
+        for classifier in &self.classifiers {
+            if !metadata21.classifier.contains(classifier) {
+                metadata21.classifier.push(classifier.clone());
+            }
+        }
+        for project_url in &self.project_urls {
+            let label = project_url.split(',').next();
+            metadata21
+                .project_url
+                .retain(|existing| existing.split(',').next() != label);
+            metadata21.project_url.push(project_url.clone());
+        }
+    }
+
+    /// Collects the entry points from Cargo.toml, pyproject.toml and the command line, with the
+    /// later ones overriding entries of the same name in the earlier ones.
+    fn entry_points(&self, manifests: &Manifests) -> Result<EntryPoints> {
+        let mut entry_points = EntryPoints::new();
+        let scripts = manifests.cargo_toml.scripts();
+        if !scripts.is_empty() {
+            entry_points.insert(
+                entry_points::CONSOLE_SCRIPTS.to_string(),
+                scripts.into_iter().collect(),
+            );
+        }
+
+        let pyproject_entry_points =
+            entry_points::from_pyproject_toml(manifests.pyproject.as_ref())
+                .map_err(Error::manifest)?;
+        for (group, entries) in pyproject_entry_points {
+            entry_points.entry(group).or_default().extend(entries);
+        }
+
+        entry_points::add_entry_point_args(&mut entry_points, &self.entry_points);
+        Ok(entry_points)
+    }
+
+    /// Collects the files for the wheel's .data directory from the `data` directory in
+    /// `[package.metadata.maturin-nix]` and the command line. maturin rejects unknown keys in
+    /// `[package.metadata.maturin]`.
+    fn data_files(&self, manifests: &Manifests, args: &[data::DataArg]) -> Result<DataFiles> {
+        let manifest_dir = self.manifest_path.parent().unwrap();
+
+        let data_dir = manifests
+            .manifest
+            .get("package")
+            .and_then(|package| package.get("metadata"))
+            .and_then(|metadata| metadata.get("maturin-nix"))
+            .and_then(|maturin_nix| maturin_nix.get("data"));
+        let mut data_files = match data_dir {
+            Some(toml::Value::String(data_dir)) => {
+                data::from_data_dir(&manifest_dir.join(data_dir)).map_err(Error::manifest)?
+            }
+            Some(_) => {
+                return Err(Error::manifest(format_err!(
+                    "data in [package.metadata.maturin-nix] in {} must be a path",
+                    self.manifest_path.display()
+                )))
+            }
+            None => DataFiles::new(),
+        };
+        data::add_data_args(&mut data_files, args).map_err(Error::usage)?;
+        Ok(data_files)
+    }
+
+    /// Finds the license files that go into `.dist-info/licenses`
+    fn license_files(&self, manifests: &Manifests) -> Result<Vec<(String, PathBuf)>> {
+        let manifest_dir = self.manifest_path.parent().unwrap();
+
+        // The license file of pyproject.toml takes precedence, like the rest of its metadata
+        let pyproject_license_file =
+            pyproject::license_file(manifests.pyproject.as_ref()).map_err(Error::manifest)?;
+        let cargo_license_file = manifests
+            .manifest
+            .get("package")
+            .and_then(|package| package.get("license-file"));
+        let license_file = match (&pyproject_license_file, cargo_license_file) {
+            (Some(license_file), _) => Some(license_file.as_str()),
+            (None, Some(toml::Value::String(license_file))) => Some(license_file.as_str()),
+            (None, Some(_)) => {
+                return Err(Error::manifest(format_err!(
+                    "license-file in {} must be a path",
+                    self.manifest_path.display()
+                )))
+            }
+            (None, None) => None,
+        };
+        licenses::find_license_files(manifest_dir, license_file)
+            .context(format!(
+                "Invalid license files for {}",
+                self.manifest_path.display()
+            ))
+            .map_err(Error::manifest)
+    }
+
+    /// Splits the module name into the path of its package inside the wheel and the name of the
+    /// native module itself, e.g. "mypkg._native" into "mypkg" and "_native".
+    fn module_path(&self) -> (PathBuf, &str) {
+        let mut parts: Vec<&str> = self.module_name.split('.').collect();
+        let name = parts.pop().unwrap();
+        (parts.iter().collect(), name)
+    }
+
+    /// Returns the name cargo uses for the artifact: the name of the library target, or of the
+    /// first binary for bin. Both default to the package name.
+    fn artifact_name(&self, manifests: &Manifests, bridge: &BridgeModel) -> Result<String> {
+        let manifest = &manifests.manifest;
+        let target_name = match bridge {
+            BridgeModel::Bin => manifest
+                .get("bin")
+                .and_then(|bins| bins.get(0))
+                .and_then(|bin| bin.get("name")),
+            _ => manifest.get("lib").and_then(|lib| lib.get("name")),
+        };
+        if let Some(name) = target_name.and_then(toml::Value::as_str) {
+            return Ok(name.to_string());
+        }
+
+        let package_name = self.package_name(manifests)?;
+        match bridge {
+            BridgeModel::Bin => Ok(package_name),
+            _ => Ok(package_name.replace('-', "_")),
+        }
+    }
+
+    fn package_name(&self, manifests: &Manifests) -> Result<String> {
+        let package_name = manifests
+            .manifest
+            .get("package")
+            .and_then(|package| package.get("name"))
+            .and_then(toml::Value::as_str)
+            .ok_or_else(|| Error::manifest(format_err!("the manifest has no package name")))?;
+        Ok(package_name.to_string())
+    }
+
+    fn bridge(&self, manifests: &Manifests) -> Result<BridgeModel> {
+        match self.bindings.as_deref() {
+            Some("cffi") => return Ok(BridgeModel::Cffi),
+            Some("bin") => return Ok(BridgeModel::Bin),
+            Some(bindings) => return Ok(BridgeModel::Bindings(bindings.replace('-', "_"))),
+            None => {}
+        }
+
+        let manifest = &manifests.manifest;
+
+        let dependencies = manifest.get("dependencies").and_then(toml::Value::as_table);
+        let depends_on = |name: &str| {
+            dependencies.into_iter().flatten().any(|(key, dep)| {
+                let package = dep.get("package").and_then(toml::Value::as_str);
+                package.unwrap_or(key) == name
+            })
+        };
+
+        if depends_on("pyo3") {
+            return Ok(BridgeModel::Bindings("pyo3".to_string()));
+        }
+        if depends_on("cpython") {
+            return Ok(BridgeModel::Bindings("rust_cpython".to_string()));
+        }
+
+        let is_cdylib = manifest
+            .get("lib")
+            .and_then(|lib| lib.get("crate-type"))
+            .and_then(toml::Value::as_array)
+            .into_iter()
+            .flatten()
+            .any(|ty| ty.as_str() == Some("cdylib"));
+        if is_cdylib {
+            return Ok(BridgeModel::Cffi);
+        }
+
+        let manifest_dir = self.manifest_path.parent().unwrap();
+        if manifest.get("bin").is_some() || manifest_dir.join("src/main.rs").is_file() {
+            return Ok(BridgeModel::Bin);
+        }
+
+        Err(Error::manifest(format_err!(
+            "couldn't detect the bindings of the crate, please pass --bindings"
+        )))
+    }
+
+    /// Returns the wheels to build: one per interpreter for bindings, or a single abi3 one if
+    /// requested, and a single universal one for cffi and bin.
+    fn wheel_specs(
+        &self,
+        bridge: &BridgeModel,
+        python_interpreters: &[PythonInterpreter],
+        platform: &Platform,
+    ) -> Result<Vec<WheelSpec>> {
+        let (package, name) = self.module_path();
+        let wheels = match (bridge, self.abi3) {
+            (BridgeModel::Bindings(_), Some((major, minor))) => {
+                let tag = format!("cp{}{}-abi3-{}", major, minor, platform.platform_tag());
+                vec![WheelSpec {
+                    tags: platform::expand_tag(&tag),
+                    tag,
+                    library_path: Some(package.join(platform.abi3_library_name(name))),
+                    interpreter: None,
+                }]
+            }
+            (BridgeModel::Bindings(_), None) => python_interpreters
+                .iter()
+                .map(|py| {
+                    let tag = platform.interpreter_tag(py);
+                    WheelSpec {
+                        tags: platform::expand_tag(&tag),
+                        tag,
+                        library_path: Some(package.join(platform.library_name(name, py))),
+                        interpreter: Some(py.clone()),
+                    }
+                })
+                .collect(),
+            (BridgeModel::Cffi, Some(_)) | (BridgeModel::Bin, Some(_)) => {
+                return Err(Error::usage(format_err!(
+                    "--abi3 can only be used with pyo3 or rust-cpython bindings"
+                )));
+            }
+            (BridgeModel::Cffi, None) | (BridgeModel::Bin, None) => {
+                let (tag, tags) = platform.universal_tags();
+                vec![WheelSpec {
+                    tag,
+                    tags,
+                    library_path: None,
+                    interpreter: None,
+                }]
+            }
+        };
+        Ok(wheels)
+    }
+}
+
+/// Parses a python version of the form "3.7"
+fn parse_python_version(version: &str) -> std::result::Result<(usize, usize), String> {
+    let mut parts = version.splitn(2, '.');
+    let major = parts.next().and_then(|major| major.parse().ok());
+    let minor = parts.next().and_then(|minor| minor.parse().ok());
+    match (major, minor) {
+        (Some(3), Some(minor)) => Ok((3, minor)),
+        _ => Err(format!(
+            "expected a python version like 3.7, got {}",
+            version
+        )),
     }
 }
 
+/// Rejects versions that would break the wheel's file name
+fn parse_version(version: &str) -> std::result::Result<String, String> {
+    if version.is_empty() || version.contains(char::is_whitespace) {
+        return Err(format!(
+            "expected a version like 1.0.0.post1, got {:?}",
+            version
+        ));
+    }
+    Ok(version.to_string())
+}
+
+fn parse_platform_tag(platform_tag: &str) -> std::result::Result<String, String> {
+    if platform_tag.is_empty() || platform_tag.contains(|c: char| c == '-' || c.is_whitespace()) {
+        return Err(format!(
+            "expected a platform tag like manylinux2014_x86_64, got {:?}",
+            platform_tag
+        ));
+    }
+    Ok(platform_tag.to_string())
+}
+
+fn parse_project_url(project_url: &str) -> std::result::Result<String, String> {
+    match project_url.split_once(',') {
+        Some((label, url)) if !label.trim().is_empty() && !url.trim().is_empty() => {
+            Ok(format!("{}, {}", label.trim(), url.trim()))
+        }
+        _ => Err(format!(
+            "expected \"label, url\" like \"Homepage, https://example.org\", got {}",
+            project_url
+        )),
+    }
+}
+
+/// Returns the normalized distribution name a requirement like "Foo_Bar[x]>=1.0" is on, which
+/// is what tells two requirements on the same distribution apart
+fn requirement_name(requirement: &str) -> String {
+    let mut name = String::new();
+    for c in requirement.trim().chars() {
+        match c {
+            // Runs of separators are one dash, as in PEP 503
+            '-' | '_' | '.' if name.ends_with('-') => {}
+            '-' | '_' | '.' => name.push('-'),
+            c if c.is_ascii_alphanumeric() => name.push(c.to_ascii_lowercase()),
+            _ => break,
+        }
+    }
+    name
+}
+
+/// Finds the interpreters relevant to the bridge: all of them for bindings, where each gets its
+/// own wheel, any for cffi, where one is needed to generate the declarations, and none for bin
+/// or abi3 wheels.
+///
+/// Explicitly given interpreters are used instead of searching PATH, and requested python
+/// versions narrow down the result. Any of those that can't be found is an error.
+///
+/// The interpreters always run on this machine, even when building for another platform.
+fn find_interpreters(
+    platform: &Platform,
+    bridge: &BridgeModel,
+    info: &Info,
+) -> Result<Vec<PythonInterpreter>> {
+    match bridge {
+        BridgeModel::Bin => return Ok(Vec::new()),
+        BridgeModel::Bindings(_) if info.abi3.is_some() => return Ok(Vec::new()),
+        BridgeModel::Bindings(_) => platform.check_interpreters_usable().map_err(Error::usage)?,
+        BridgeModel::Cffi => {}
+    }
+
+    let target = &Target::current();
+
+    let executables: Vec<PathBuf> = if !info.interpreters.is_empty() {
+        info.interpreters.clone()
+    } else if !info.python_versions.is_empty() && target.is_unix() {
+        // Asking for the versions directly avoids tripping over unrelated broken interpreters
+        info.python_versions
+            .iter()
+            .map(|(major, minor)| PathBuf::from(format!("python{}.{}", major, minor)))
+            .collect()
+    } else {
+        let python_interpreters = PythonInterpreter::find_all(target, bridge)
+            .context("Failed to search for python interpreters")
+            .map_err(Error::interpreter)?;
+        return filter_python_versions(python_interpreters, &info.python_versions);
+    };
+
+    let mut python_interpreters = Vec::new();
+    for executable in executables {
+        let python_interpreter = PythonInterpreter::check_executable(&executable, target, bridge)
+            .context(format!(
+                "Couldn't introspect python interpreter {}",
+                executable.display()
+            ))
+            .map_err(Error::interpreter)?
+            .ok_or_else(|| {
+                Error::interpreter(format_err!(
+                    "python interpreter {} not found",
+                    executable.display()
+                ))
+            })?;
+        python_interpreters.push(python_interpreter);
+    }
+
+    filter_python_versions(python_interpreters, &info.python_versions)
+}
+
+/// Keeps only the interpreters with one of the given versions, if any are given, and errors if
+/// one of the versions has no interpreter.
+fn filter_python_versions(
+    python_interpreters: Vec<PythonInterpreter>,
+    python_versions: &[(usize, usize)],
+) -> Result<Vec<PythonInterpreter>> {
+    if python_versions.is_empty() {
+        return Ok(python_interpreters);
+    }
+
+    for (major, minor) in python_versions {
+        if !python_interpreters
+            .iter()
+            .any(|py| py.major == *major && py.minor == *minor)
+        {
+            return Err(Error::interpreter(format_err!(
+                "no python {}.{} interpreter found",
+                major,
+                minor
+            )));
+        }
+    }
+
+    Ok(python_interpreters
+        .into_iter()
+        .filter(|py| python_versions.contains(&(py.major, py.minor)))
+        .collect())
+}
+
+/// A wheel that gets built for the crate
+struct WheelSpec {
+    /// The tag used in the wheel name
+    tag: String,
+    /// The tags for the WHEEL file
+    tags: Vec<String>,
+    /// The path of the extension module inside the wheel; only set for bindings
+    library_path: Option<PathBuf>,
+    /// The interpreter the wheel is built for; only set for bindings without abi3
+    interpreter: Option<PythonInterpreter>,
+}
+
+/// The description of a wheel printed by `wheel-names --format json`
+#[derive(Serialize)]
+struct WheelNameJson {
+    filename: String,
+    tag: TagJson,
+    interpreter: Option<InterpreterJson>,
+    name: String,
+    distribution: String,
+    version: String,
+}
+
+/// A tag split into its python, abi and platform parts as in PEP 425
+#[derive(Serialize)]
+struct TagJson {
+    python: String,
+    abi: String,
+    platform: String,
+}
+
+#[derive(Serialize)]
+struct InterpreterJson {
+    path: PathBuf,
+    implementation: String,
+    version: String,
+}
+
+/// The options of build that wheel-names doesn't need
+#[derive(Debug, StructOpt)]
+struct BuildArgs {
+    /// The path to the rustc artifact for a library. This library must have a crate-type of
+    /// "cdylib". On macOS the library should also be compiled with
+    ///  "-C link-arg=-undefined -C link-arg=dynamic_lookup"; For bin bindings this is the
+    ///  path to the executable instead. The artifact is checked to be of the right kind and
+    ///  target and, for pyo3 and rust-cpython, to export the init function of the module.
+    ///  When omitted, it's looked up in the target directory cargo metadata reports for the
+    ///  profile and --target.
+    #[structopt(long)]
+    artifact_path: Option<PathBuf>,
+
+    /// Take the artifact from the output of `cargo build --message-format=json`, in a file or
+    /// on stdin with "-", instead of --artifact-path. It's the cdylib, or the binary for bin
+    /// bindings, built for the package in the manifest.
+    #[structopt(long, value_name = "file|-", conflicts_with = "artifact-path")]
+    cargo_messages: Option<PathBuf>,
+
+    /// Build the crate with `cargo rustc` as a cdylib, or the binary for bin bindings, and
+    /// package what it produced. By default the artifact is expected to be built already, as
+    /// in nix builds.
+    #[structopt(
+        long,
+        conflicts_with_all = &["artifact-path", "cargo-messages"]
+    )]
+    cargo_build: bool,
+
+    /// Look for the artifact built with --release, or build it that way with --cargo-build,
+    /// when --artifact-path is omitted.
+    #[structopt(long, conflicts_with = "profile")]
+    release: bool,
+
+    /// Look for the artifact built with the given cargo profile, or build it that way with
+    /// --cargo-build, when --artifact-path is omitted. Defaults to dev.
+    #[structopt(long, value_name = "name")]
+    profile: Option<String>,
+
+    /// Features to enable with --cargo-build, or the ones the artifact was built with for
+    /// --bundle-third-party-licenses. Can be given multiple times.
+    #[structopt(long = "features", value_name = "features")]
+    features: Vec<String>,
+
+    /// Enable all features with --cargo-build or --bundle-third-party-licenses.
+    #[structopt(long)]
+    all_features: bool,
+
+    /// Disable the default features with --cargo-build or --bundle-third-party-licenses.
+    #[structopt(long)]
+    no_default_features: bool,
+
+    /// The directory to store the output wheel.
+    #[structopt(long)]
+    output_dir: PathBuf,
+
+    /// A directory with python packages to add to the wheel, for projects where python code
+    /// wraps the native module. Use a dotted module name to put the native module inside one
+    /// of those packages.
+    #[structopt(long)]
+    python_source: Option<PathBuf>,
+
+    /// Type stubs for the native module: a file named after it like "_native.pyi", or a
+    /// directory laid out like the wheel with the module's stub, e.g. "mypkg/_native.pyi". The
+    /// package gets a py.typed marker as PEP 561 asks for.
+    #[structopt(long, value_name = "file.pyi|dir")]
+    stubs: Option<PathBuf>,
+
+    /// Adds a file or the contents of a directory to one of the wheel's install schemes as
+    /// `scheme=path`, e.g. "headers=include" for C headers. The schemes are scripts, headers,
+    /// data, purelib and platlib. Scripts are made executable and a python shebang becomes
+    /// `#!python`, which installers point to the right interpreter. Adds to the `data`
+    /// directory in `[package.metadata.maturin-nix]` in Cargo.toml, which has a subdirectory for
+    /// each scheme. Can be given multiple times.
+    #[structopt(
+        long = "data",
+        value_name = "scheme=path",
+        parse(try_from_str = data::parse_data_arg)
+    )]
+    data: Vec<data::DataArg>,
+
+    /// Build every wheel a second time in a temporary directory and fail if the two differ.
+    /// Set SOURCE_DATE_EPOCH to control the timestamps in the wheel.
+    #[structopt(long)]
+    check_reproducible: bool,
+
+    /// Remove references to the nix store from the artifact: store entries from the RPATH or
+    /// RUNPATH and the hashes of all other store paths, which are replaced with e's like
+    /// `remove-references-to` does. Without this, references are only reported.
+    #[structopt(long)]
+    scrub_store_paths: bool,
+
+    /// A store path, or the start of the name after its hash like "glibc", that may stay in
+    /// the artifact. Can be given multiple times.
+    #[structopt(long = "allow-store-path", value_name = "path-or-name")]
+    allowed_store_paths: Vec<String>,
+
+    /// Copy the shared libraries the artifact needs, except the ones every linux system has,
+    /// into a `<package>.libs` directory in the wheel, with a hash in their names, and point the
+    /// RUNPATH of the artifact there. Like `auditwheel repair`, but without patchelf: the new
+    /// names are written over the old RPATH or RUNPATH, which nix always sets.
+    #[structopt(long)]
+    bundle_libs: bool,
+
+    /// Write the licenses of the crates the artifact statically links into a
+    /// THIRD_PARTY_LICENSES file in the .dist-info directory. The dependencies of the target
+    /// come from Cargo.lock and their licenses from the vendor directory or cargo registry the
+    /// crate was built with. Nothing is downloaded. Optional dependencies only count with the
+    /// --features that enable them.
+    #[structopt(long)]
+    bundle_third_party_licenses: bool,
+}
+
 /// Build python wheels
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = "maturin-nix",
     about = "Tool for building pyo3 wheels inside nix",
-    global_settings(&[AppSettings::ColoredHelp, AppSettings::VersionlessSubcommands])
+    global_settings(&[AppSettings::ColoredHelp, AppSettings::VersionlessSubcommands]),
+    after_help = error::EXIT_CODES_HELP
 )]
-
 enum Opt {
     #[structopt(name = "wheel-names")]
     /// Prints out the names of wheels that will be generated. The name of wheel is determined by
@@ -46,9 +774,15 @@ enum Opt {
         #[structopt(flatten)]
         info: Info,
 
-        /// Expect a single python version to be available and will error if not.
+        /// Expect a single python version to be available and will error if not. Only relevant
+        /// for pyo3 and rust-cpython bindings, which get one wheel per python version.
         #[structopt(long)]
         expect_one: bool,
+
+        /// Print one file name per line (text) or a JSON list that describes each wheel with its
+        /// file name, tag, interpreter and package name and version (json).
+        #[structopt(long, default_value = "text", possible_values = &["text", "json"])]
+        format: String,
     },
 
     #[structopt(name = "build")]
@@ -57,86 +791,986 @@ enum Opt {
         #[structopt(flatten)]
         info: Info,
 
-        /// The path to the rustc artifact for a library. This library must have a crate-type of
-        /// "cdylib". On macOS the library should also be compiled with
-        ///  "-C link-arg=-undefined -C link-arg=dynamic_lookup";
-        #[structopt(long)]
-        artifact_path: PathBuf,
+        #[structopt(flatten)]
+        options: BuildArgs,
+    },
 
-        /// The directory to store the output wheel.
+    #[structopt(name = "sdist")]
+    /// Build a source distribution with the files cargo would package, following `include` and
+    /// `exclude` in Cargo.toml, PKG-INFO and a pyproject.toml, which is generated if the crate
+    /// doesn't have one.
+    Sdist {
+        #[structopt(flatten)]
+        info: Info,
+
+        /// The directory to store the source distribution.
         #[structopt(long)]
         output_dir: PathBuf,
     },
+
+    #[structopt(name = "nix-expr")]
+    /// Prints a nix expression that builds the crate with buildRustPackage and installs the wheel
+    /// with buildPythonPackage. Save it next to Cargo.toml and call it with
+    /// `python3Packages.callPackage`, with maturin-nix in scope, e.g. from an overlay or as
+    /// `callPackage ./default.nix { inherit maturin-nix; }`. The metadata options are passed on
+    /// to the build in the expression.
+    NixExpr {
+        #[structopt(flatten)]
+        info: Info,
+    },
+
+    #[structopt(name = "verify")]
+    /// Check an existing wheel: that the tags in its file name match WHEEL, that RECORD matches
+    /// the contents, that METADATA is valid and that native modules export the PyInit_ function
+    /// for their name. Reports all problems found.
+    Verify {
+        /// The wheel to check
+        wheel: PathBuf,
+    },
 }
 
-fn main() {
-    let opt = Opt::from_args();
+fn wheel_names(info: Info, expect_one: bool, format: &str) -> Result<()> {
+    let manifests = info.manifests()?;
+    let metadata21 = info.meta21(&manifests)?;
+    let bridge = info.bridge(&manifests)?;
+    let platform = info.platform()?;
+    let python_interpreters = find_interpreters(&platform, &bridge, &info)?;
 
-    let target = Target::current();
-    let bridge = BridgeModel::Cffi;
-    let python_interpreters =
-        PythonInterpreter::find_all(&target, &bridge).expect("python_interpreter");
+    let is_bindings = matches!(bridge, BridgeModel::Bindings(_));
+    if expect_one && is_bindings && info.abi3.is_none() && python_interpreters.len() != 1 {
+        if python_interpreters.is_empty() {
+            return Err(Error::interpreter(format_err!("no python versions found")));
+        }
 
-    // manylinux basically says that there should be a bunch of standard libraries in standard
-    // places. This doesn't play nicely with nix so we don't use it.
-    let manylinux = Manylinux::Off;
+        let mut message = "multiple python versions found:".to_string();
+        for py in &python_interpreters {
+            message += &format!("\n  {}", py);
+        }
+        return Err(Error::interpreter(failure::err_msg(message)));
+    }
 
-    match opt {
-        Opt::WheelNames { info, expect_one } => {
-            let metadata21 = info.meta21();
+    let wheels = info.wheel_specs(&bridge, &python_interpreters, &platform)?;
+    let filename = |wheel: &WheelSpec| {
+        format!(
+            "{}-{}-{}.whl",
+            metadata21.get_distribution_escaped(),
+            metadata21.get_version_escaped(),
+            wheel.tag
+        )
+    };
 
-            if expect_one && python_interpreters.len() != 1 {
-                let err = ansi_term::Color::Red.bold().paint("error:");
-                if python_interpreters.is_empty() {
-                    eprintln!("{} no python versions found", err);
-                    process::exit(1);
+    if format == "json" {
+        let descriptions: Vec<WheelNameJson> = wheels
+            .iter()
+            .map(|wheel| {
+                // Platform tags never contain dashes, but the python tag can contain dots
+                let mut parts = wheel.tag.splitn(3, '-').map(ToString::to_string);
+                WheelNameJson {
+                    filename: filename(wheel),
+                    tag: TagJson {
+                        python: parts.next().unwrap_or_default(),
+                        abi: parts.next().unwrap_or_default(),
+                        platform: parts.next().unwrap_or_default(),
+                    },
+                    interpreter: wheel.interpreter.as_ref().map(|py| InterpreterJson {
+                        path: py.executable.clone(),
+                        implementation: py.interpreter.to_string(),
+                        version: format!("{}.{}", py.major, py.minor),
+                    }),
+                    name: metadata21.name.clone(),
+                    distribution: metadata21.get_distribution_escaped(),
+                    version: metadata21.version.clone(),
                 }
-                eprintln!("{} multiple python versions found:", err);
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&descriptions).unwrap());
+    } else {
+        for wheel in &wheels {
+            println!("{}", filename(wheel));
+        }
+    }
 
-                for py in &python_interpreters {
-                    eprintln!("  {}", py);
-                }
-                process::exit(1);
-            }
+    Ok(())
+}
+
+fn build(info: Info, options: &BuildArgs) -> Result<()> {
+    let output_dir = options.output_dir.as_path();
+    let python_source = options.python_source.as_deref();
 
-            for py in python_interpreters {
-                let tag = py.get_tag(&manylinux);
-                let wheel_path = format!(
-                    "{}-{}-{}.whl",
-                    metadata21.get_distribution_escaped(),
-                    metadata21.get_version_escaped(),
-                    tag
-                );
-                println!("{}", wheel_path);
+    let manifests = info.manifests()?;
+    let metadata21 = info.meta21(&manifests)?;
+    let entry_points = info.entry_points(&manifests)?;
+    let data_files = info.data_files(&manifests, &options.data)?;
+    let license_files = info.license_files(&manifests)?;
+    let has_features =
+        !options.features.is_empty() || options.all_features || options.no_default_features;
+    if has_features && !options.cargo_build && !options.bundle_third_party_licenses {
+        return Err(Error::usage(format_err!(
+            "--features, --all-features and --no-default-features need --cargo-build or \
+             --bundle-third-party-licenses"
+        )));
+    }
+    let third_party_licenses = if options.bundle_third_party_licenses {
+        let text = third_party::third_party_licenses(
+            &info.manifest_path,
+            info.target.as_deref(),
+            &options.features(),
+        )
+        .context("Failed to collect the third party licenses")
+        .map_err(Error::manifest)?;
+        Some(text)
+    } else {
+        None
+    };
+    let bridge = info.bridge(&manifests)?;
+    let platform = info.platform()?;
+    let python_interpreters = find_interpreters(&platform, &bridge, &info)?;
+
+    let (package, _) = info.module_path();
+    let has_package = !package.as_os_str().is_empty();
+    if let Some(python_source) = python_source.filter(|_| has_package) {
+        if !python_source.join(&package).join("__init__.py").is_file() {
+            return Err(Error::usage(format_err!(
+                "the package {} of module {} has no __init__.py in {}",
+                package.display(),
+                info.module_name,
+                python_source.display()
+            )));
+        }
+    }
+
+    let stubs = match &options.stubs {
+        Some(stubs_path) => Some(collect_stubs(&info, &bridge, stubs_path, python_source)?),
+        None => None,
+    };
+
+    if bridge == BridgeModel::Cffi && python_interpreters.is_empty() {
+        return Err(Error::interpreter(format_err!(
+            "no python versions found to generate the cffi declarations"
+        )));
+    }
+
+    let package_name = info.package_name(&manifests)?;
+    let artifact_name = info.artifact_name(&manifests, &bridge)?;
+    let artifact_path = match (&options.artifact_path, &options.cargo_messages) {
+        (Some(artifact_path), _) => artifact_path.clone(),
+        (None, Some(messages)) => {
+            artifact_from_messages(messages, &package_name, &artifact_name, &bridge, &platform)?
+        }
+        (None, None) if options.cargo_build => run_cargo_build(
+            &info,
+            options,
+            &package_name,
+            &artifact_name,
+            &bridge,
+            &platform,
+        )?,
+        (None, None) => find_artifact(&info, options, &artifact_name, &bridge, &platform)?,
+    };
+    let artifact_path = artifact_path.as_path();
+
+    // Catch a wrong artifact before anything is written
+    artifact::check_artifact(artifact_path, &platform, &bridge, &info.module_name)
+        .map_err(Error::artifact)?;
+
+    // Holds the patched copies of the artifact and the bundled libraries until the wheels are
+    // written
+    let bundle_dir;
+    let (bundled_artifact_path, mut bundled_libs) = if options.bundle_libs {
+        bundle_dir = tempfile::tempdir()
+            .context("Failed to create a temporary directory")
+            .map_err(Error::wheel)?;
+        bundle_libs(&info, &bridge, &platform, artifact_path, bundle_dir.path())?
+    } else {
+        (artifact_path.to_path_buf(), Vec::new())
+    };
+
+    // Checked after bundling, since the bundled libraries don't have to be on the system
+    let bundled_names: Vec<String> = bundled_libs
+        .iter()
+        .map(|(_, library_path)| {
+            library_path
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_string()
+        })
+        .collect();
+    artifact::check_compatibility(&bundled_artifact_path, &platform, &bundled_names)
+        .context(format!(
+            "{} can't be tagged as {}",
+            artifact_path.display(),
+            platform.platform_tag()
+        ))
+        .map_err(Error::artifact)?;
+    let artifact_path = bundled_artifact_path;
+
+    let allow_list = store_paths::AllowList(&options.allowed_store_paths);
+    // Holds the scrubbed copies until the wheels are written
+    let scrub_dir;
+    let artifact_path = if options.scrub_store_paths {
+        scrub_dir = tempfile::tempdir()
+            .context("Failed to create a temporary directory")
+            .map_err(Error::wheel)?;
+        for (_, library_path) in &mut bundled_libs {
+            *library_path = scrub_artifact(library_path, scrub_dir.path(), &allow_list)?;
+        }
+        scrub_artifact(&artifact_path, scrub_dir.path(), &allow_list)?
+    } else {
+        for (_, library_path) in &bundled_libs {
+            report_store_paths(library_path, &allow_list)?;
+        }
+        report_store_paths(&artifact_path, &allow_list)?;
+        artifact_path
+    };
+    let artifact_path = artifact_path.as_path();
+
+    let write_wheel = |wheel: &WheelSpec, wheel_dir: &Path| -> Result<PathBuf> {
+        let mut writer = wheel_writer::WheelWriter::new(
+            &wheel.tag,
+            wheel_dir,
+            &metadata21,
+            &wheel.tags,
+            &license_files,
+        )
+        .context(format!(
+            "Failed to create a wheel in {}",
+            wheel_dir.display()
+        ))
+        .map_err(Error::wheel)?;
+
+        module_writer::write_entry_points(&mut writer, &metadata21, &entry_points)
+            .map_err(Error::wheel)?;
+
+        if let Some(python_source) = python_source {
+            module_writer::write_python_part(&mut writer, python_source)
+                .context(format!(
+                    "Failed to add the python source in {}",
+                    python_source.display()
+                ))
+                .map_err(Error::wheel)?;
+        }
+
+        match &wheel.library_path {
+            Some(library_path) => {
+                module_writer::write_bindings_module(&mut writer, library_path, artifact_path)
+            }
+            None if bridge == BridgeModel::Bin => {
+                module_writer::write_bin(&mut writer, artifact_path, &metadata21)
             }
+            None => module_writer::write_cffi_module(
+                &mut writer,
+                info.manifest_path.parent().unwrap(),
+                &info.module_name.replace('.', "/"),
+                artifact_path,
+                &python_interpreters[0].executable,
+            ),
         }
-        Opt::Build {
-            info,
-            artifact_path,
-            output_dir,
-        } => {
-            for python_interpreter in python_interpreters {
-                let tag = python_interpreter.get_tag(&manylinux);
-
-                let mut writer = WheelWriter::new(
-                    &tag,
-                    &output_dir,
-                    &info.meta21(),
-                    &std::collections::HashMap::default(),
-                    &[tag.clone()],
-                )
-                .expect("writer");
-
-                let so_filename = python_interpreter.get_library_name(&info.module_name);
-
-                writer
-                    .add_file(so_filename, &artifact_path)
-                    .expect("add files");
-
-                let wheel_path = writer.finish().expect("writer finish");
-
-                eprintln!("📦 successfuly created wheel {}", wheel_path.display());
+        .context("Failed to add the native module to the wheel")
+        .map_err(Error::wheel)?;
+
+        if let Some(third_party_licenses) = &third_party_licenses {
+            module_writer::write_third_party_licenses(
+                &mut writer,
+                &metadata21,
+                third_party_licenses,
+            )
+            .map_err(Error::wheel)?;
+        }
+
+        module_writer::write_data(&mut writer, &metadata21, &data_files)
+            .context("Failed to add the data files to the wheel")
+            .map_err(Error::wheel)?;
+
+        if let Some(stubs) = &stubs {
+            module_writer::write_stubs(&mut writer, stubs)
+                .context("Failed to add the type stubs to the wheel")
+                .map_err(Error::wheel)?;
+        }
+
+        for (target, library_path) in &bundled_libs {
+            let bytes = fs::read(library_path)
+                .context(format!("Can't read {}", library_path.display()))
+                .map_err(Error::wheel)?;
+            writer
+                .add_bytes_with_permissions(target, &bytes, 0o755)
+                .map_err(Error::wheel)?;
+        }
+
+        writer.finish().map_err(Error::wheel)
+    };
+
+    for wheel in info.wheel_specs(&bridge, &python_interpreters, &platform)? {
+        let wheel_path = write_wheel(&wheel, output_dir)?;
+
+        if options.check_reproducible {
+            let temp_dir = tempfile::tempdir()
+                .context("Failed to create a temporary directory")
+                .map_err(Error::wheel)?;
+            let second_path = write_wheel(&wheel, temp_dir.path())?;
+            let (first, second) = (file_sha256(&wheel_path)?, file_sha256(&second_path)?);
+            if first != second {
+                return Err(Error::wheel(format_err!(
+                    "{} is not reproducible: building it twice gave sha256 {} and {}",
+                    wheel_path.display(),
+                    first,
+                    second
+                )));
             }
         }
+
+        eprintln!("📦 successfuly created wheel {}", wheel_path.display());
+    }
+
+    Ok(())
+}
+
+impl BuildArgs {
+    /// Returns the cargo profile of --profile or --release
+    fn features(&self) -> cargo::Features<'_> {
+        cargo::Features {
+            features: &self.features,
+            all_features: self.all_features,
+            no_default_features: self.no_default_features,
+        }
+    }
+
+    fn profile(&self) -> &str {
+        match (&self.profile, self.release) {
+            (Some(profile), _) => profile,
+            (None, true) => "release",
+            (None, false) => "dev",
+        }
+    }
+}
+
+/// Collects the stubs of `--stubs`, leaving out the py.typed marker if the python source
+/// already has one
+fn collect_stubs(
+    info: &Info,
+    bridge: &BridgeModel,
+    stubs_path: &Path,
+    python_source: Option<&Path>,
+) -> Result<stubs::Stubs> {
+    let (package, name) = info.module_path();
+    let module_stub = match bridge {
+        BridgeModel::Bindings(_) => package.join(format!("{}.pyi", name)),
+        BridgeModel::Cffi => PathBuf::from(info.module_name.replace('.', "/")).join("__init__.pyi"),
+        BridgeModel::Bin => {
+            return Err(Error::usage(format_err!(
+                "--stubs doesn't work with bin bindings, which have no module"
+            )))
+        }
+    };
+
+    let mut stubs = stubs::collect_stubs(stubs_path, &module_stub).map_err(Error::usage)?;
+    if let Some(python_source) = python_source {
+        if let Some((target, _)) = stubs
+            .files
+            .iter()
+            .find(|(target, _)| python_source.join(target).exists())
+        {
+            return Err(Error::usage(format_err!(
+                "Both --stubs and --python-source have {}",
+                target.display()
+            )));
+        }
+    }
+    match (&stubs.py_typed, python_source) {
+        (Some(top_level), Some(python_source))
+            if python_source.join(top_level).join("py.typed").is_file() =>
+        {
+            stubs.py_typed = None;
+        }
+        (None, _) if module_stub.parent() == Some(Path::new("")) => eprintln!(
+            "⚠️  PEP 561 only covers packages, so type checkers may ignore the stubs of the \
+             top-level module {}. Use a dotted --module-name to put it into a package.",
+            info.module_name
+        ),
+        _ => {}
+    }
+    Ok(stubs)
+}
+
+/// Looks up the artifact cargo built for the crate, for when `--artifact-path` is omitted
+fn find_artifact(
+    info: &Info,
+    options: &BuildArgs,
+    artifact_name: &str,
+    bridge: &BridgeModel,
+    platform: &Platform,
+) -> Result<PathBuf> {
+    let profile = options.profile();
+    let file_name = platform.artifact_file_name(artifact_name, *bridge == BridgeModel::Bin);
+    let target_dir = cargo::target_dir(&info.manifest_path).map_err(Error::manifest)?;
+    let artifact_path = cargo::find_artifact(
+        &target_dir,
+        profile,
+        info.target.as_deref(),
+        platform,
+        &file_name,
+    )
+    .map_err(Error::artifact)?;
+    eprintln!("🔍 found the artifact {}", artifact_path.display());
+    Ok(artifact_path)
+}
+
+/// Picks the artifact of the crate from the messages of `cargo build --message-format=json`
+fn artifact_from_messages(
+    messages: &Path,
+    package: &str,
+    artifact_name: &str,
+    bridge: &BridgeModel,
+    platform: &Platform,
+) -> Result<PathBuf> {
+    let is_bin = *bridge == BridgeModel::Bin;
+    let file_name = platform.artifact_file_name(artifact_name, is_bin);
+
+    let artifacts = if messages == Path::new("-") {
+        cargo::artifacts_from_messages(io::stdin().lock(), package, is_bin, &file_name)
+    } else {
+        let file = File::open(messages)
+            .context(format!("Can't read {}", messages.display()))
+            .map_err(Error::usage)?;
+        cargo::artifacts_from_messages(BufReader::new(file), package, is_bin, &file_name)
+    }
+    .map_err(Error::artifact)?;
+    pick_artifact(artifacts, &file_name, package)
+}
+
+/// Builds the crate with cargo for `--cargo-build` and returns the artifact
+fn run_cargo_build(
+    info: &Info,
+    options: &BuildArgs,
+    package: &str,
+    artifact_name: &str,
+    bridge: &BridgeModel,
+    platform: &Platform,
+) -> Result<PathBuf> {
+    let is_bin = *bridge == BridgeModel::Bin;
+    let file_name = platform.artifact_file_name(artifact_name, is_bin);
+
+    let build = cargo::CargoBuild {
+        manifest_path: &info.manifest_path,
+        profile: options.profile(),
+        target: info.target.as_deref(),
+        features: options.features(),
+    };
+    let bin_name = Some(artifact_name).filter(|_| is_bin);
+    let artifacts = cargo::cargo_build(&build, platform, package, bin_name, &file_name)
+        .map_err(Error::artifact)?;
+    pick_artifact(artifacts, &file_name, package)
+}
+
+/// Returns the only artifact cargo reported for the package
+fn pick_artifact(artifacts: Vec<PathBuf>, file_name: &str, package: &str) -> Result<PathBuf> {
+    match artifacts.as_slice() {
+        [artifact] => Ok(artifact.clone()),
+        [] => Err(Error::artifact(format_err!(
+            "The cargo messages have no {} of package {}",
+            file_name,
+            package
+        ))),
+        _ => Err(Error::artifact(format_err!(
+            "The cargo messages have more than one {}, pass --artifact-path to pick one of:\n{}",
+            file_name,
+            artifacts
+                .iter()
+                .map(|path| format!("    {}", path.display()))
+                .collect::<Vec<_>>()
+                .join("\n")
+        ))),
+    }
+}
+
+/// Copies the artifact and the libraries it needs into the given directory for `--bundle-libs`
+/// and returns the copy of the artifact and where each library goes in the wheel.
+///
+/// The libraries go to `<package>.libs` next to the top-level package of the module, like
+/// auditwheel does.
+fn bundle_libs(
+    info: &Info,
+    bridge: &BridgeModel,
+    platform: &Platform,
+    artifact_path: &Path,
+    dir: &Path,
+) -> Result<(PathBuf, Vec<(PathBuf, PathBuf)>)> {
+    let artifact_dir = match bridge {
+        BridgeModel::Bindings(_) => info.module_path().0,
+        BridgeModel::Cffi => PathBuf::from(info.module_name.replace('.', "/")),
+        BridgeModel::Bin => {
+            return Err(Error::usage(format_err!(
+                "--bundle-libs doesn't work with bin bindings, whose executable is installed \
+                 outside of the package"
+            )))
+        }
+    };
+    if !matches!(platform.machine(), Machine::Elf(_)) {
+        return Err(Error::usage(format_err!(
+            "--bundle-libs only works for linux wheels, not {}",
+            platform.platform_tag()
+        )));
+    }
+
+    let top_level = info.module_name.split('.').next().unwrap();
+    let libs_dir = PathBuf::from(format!("{}.libs", top_level));
+    let mut runpath = "$ORIGIN".to_string();
+    for _ in artifact_dir.iter() {
+        runpath += "/..";
+    }
+    runpath = format!("{}/{}", runpath, libs_dir.display());
+
+    let (artifact_copy, libraries) =
+        bundle::bundle_libraries(artifact_path, &runpath, platform.compatibility(), dir)
+            .context(format!(
+                "Failed to bundle the libraries {} needs",
+                artifact_path.display()
+            ))
+            .map_err(Error::artifact)?;
+
+    let names: Vec<String> = libraries
+        .iter()
+        .map(|library| library.name.clone())
+        .collect();
+    let mut bundled_libs = Vec::new();
+    for library in libraries {
+        artifact::check_compatibility(&library.path, platform, &names)
+            .context(format!("Can't bundle {}", library.source.display()))
+            .map_err(Error::artifact)?;
+        eprintln!(
+            "🔗 bundled {} from {} as {}",
+            library.original_name,
+            library.source.display(),
+            libs_dir.join(&library.name).display()
+        );
+        bundled_libs.push((libs_dir.join(&library.name), library.path));
+    }
+    Ok((artifact_copy, bundled_libs))
+}
+
+/// Warns about the nix store paths the artifact references that aren't allowed
+fn report_store_paths(artifact_path: &Path, allow_list: &store_paths::AllowList) -> Result<()> {
+    let bytes = fs::read(artifact_path)
+        .context(format!("Can't read artifact {}", artifact_path.display()))
+        .map_err(Error::artifact)?;
+    let store_paths = store_paths::find_store_paths(&bytes, allow_list);
+    if !store_paths.is_empty() {
+        eprintln!(
+            "⚠️  {} references the nix store, which won't exist where the wheel is installed:",
+            artifact_path.display()
+        );
+        for store_path in &store_paths {
+            eprintln!("    {}", store_path);
+        }
+        eprintln!(
+            "    Pass --scrub-store-paths to remove them or --allow-store-path to keep them."
+        );
+    }
+    Ok(())
+}
+
+/// Writes a copy of the artifact without the nix store paths that aren't allowed to the given
+/// directory and returns its path, which keeps the file name for bin wheels
+fn scrub_artifact(
+    artifact_path: &Path,
+    dir: &Path,
+    allow_list: &store_paths::AllowList,
+) -> Result<PathBuf> {
+    let mut bytes = fs::read(artifact_path)
+        .context(format!("Can't read artifact {}", artifact_path.display()))
+        .map_err(Error::artifact)?;
+    let scrubbed = store_paths::scrub_store_paths(&mut bytes, allow_list)
+        .context(format!(
+            "Failed to remove the store paths from {}",
+            artifact_path.display()
+        ))
+        .map_err(Error::artifact)?;
+    for entry in &scrubbed.rpath_entries {
+        eprintln!("🧹 removed {} from the RPATH", entry);
+    }
+    for store_path in &scrubbed.zeroed {
+        eprintln!("🧹 removed the reference to {}", store_path);
+    }
+
+    let scrubbed_path = dir.join(artifact_path.file_name().unwrap());
+    fs::write(&scrubbed_path, &bytes)
+        .context(format!("Failed to write {}", scrubbed_path.display()))
+        .map_err(Error::wheel)?;
+    Ok(scrubbed_path)
+}
+
+/// Hashes a built wheel for `--check-reproducible`
+fn file_sha256(path: &Path) -> Result<String> {
+    let bytes = fs::read(path)
+        .context(format!("Can't read {}", path.display()))
+        .map_err(Error::wheel)?;
+    Ok(format!("{:x}", Sha256::digest(&bytes)))
+}
+
+fn sdist(info: Info, output_dir: &Path) -> Result<()> {
+    let manifests = info.manifests()?;
+    let metadata21 = info.meta21(&manifests)?;
+    let manifest_dir = match info.manifest_path.parent().unwrap() {
+        dir if dir.as_os_str().is_empty() => Path::new("."),
+        dir => dir,
+    };
+
+    let mut files =
+        sdist::collect_files(manifest_dir, &manifests.manifest).map_err(Error::manifest)?;
+    // An output directory inside the crate would otherwise add earlier sdists to the next one
+    if let Ok(output_dir) = fs::canonicalize(output_dir) {
+        files.retain(|file| {
+            fs::canonicalize(manifest_dir.join(file))
+                .map_or(true, |path| !path.starts_with(&output_dir))
+        });
+    }
+    let sdist_path = sdist::write_sdist(manifest_dir, &files, &metadata21, output_dir)
+        .context(format!(
+            "Failed to create a source distribution in {}",
+            output_dir.display()
+        ))
+        .map_err(Error::wheel)?;
+
+    eprintln!(
+        "📦 successfuly created source distribution {}",
+        sdist_path.display()
+    );
+    Ok(())
+}
+
+fn nix_expr(info: Info) -> Result<()> {
+    print!("{}", generate_nix_expr(&info)?);
+    Ok(())
+}
+
+fn generate_nix_expr(info: &Info) -> Result<String> {
+    let manifests = info.manifests()?;
+    let metadata21 = info.meta21(&manifests)?;
+    let bridge = info.bridge(&manifests)?;
+    if info.abi3.is_some() && !matches!(bridge, BridgeModel::Bindings(_)) {
+        return Err(Error::usage(format_err!(
+            "--abi3 can only be used with pyo3 or rust-cpython bindings"
+        )));
+    }
+
+    let options = nix_expr::NixExprOptions {
+        module_name: &info.module_name,
+        bridge: &bridge,
+        artifact_name: &info.artifact_name(&manifests, &bridge)?,
+        bindings: info.bindings.as_deref(),
+        abi3: info.abi3,
+        extra_args: &nix_build_args(info)?,
+    };
+    Ok(nix_expr::nix_expression(&metadata21, &options))
+}
+
+/// Collects the options that the build in the nix expression has to get as well, so that the
+/// wheel matches the metadata of the derivation
+fn nix_build_args(info: &Info) -> Result<Vec<(&'static str, String)>> {
+    // The expression picks both from the python package set and stdenv
+    if !info.interpreters.is_empty() {
+        return Err(Error::usage(format_err!(
+            "--interpreter can't be used with nix-expr, which builds with the python of the \
+             package set"
+        )));
+    }
+    if info.target.is_some() {
+        return Err(Error::usage(format_err!(
+            "--target can't be used with nix-expr, which builds for stdenv.hostPlatform"
+        )));
+    }
+
+    let mut args = Vec::new();
+    if let Some(version) = &info.version {
+        args.push(("--set-version", version.clone()));
+    }
+    for (major, minor) in &info.python_versions {
+        args.push(("--python-version", format!("{}.{}", major, minor)));
+    }
+    for entry_point in &info.entry_points {
+        args.push(("--entry-point", entry_point.to_string()));
+    }
+    if let Some(compatibility) = info.compatibility {
+        args.push(("--compatibility", compatibility.to_string()));
+    }
+    if let Some(platform_tag) = &info.platform_tag {
+        args.push(("--platform-tag", platform_tag.clone()));
+    }
+    if let Some(summary) = &info.summary {
+        args.push(("--summary", summary.clone()));
+    }
+    for requirement in &info.requires_dist {
+        args.push(("--requires-dist", requirement.clone()));
+    }
+    if let Some(requires_python) = &info.requires_python {
+        args.push(("--requires-python", requires_python.clone()));
+    }
+    for classifier in &info.classifiers {
+        args.push(("--classifier", classifier.clone()));
+    }
+    for project_url in &info.project_urls {
+        args.push(("--project-url", project_url.clone()));
+    }
+    Ok(args)
+}
+
+fn verify(wheel: &Path) -> Result<()> {
+    // A wheel that can't be read at all is a wrong path rather than a failed verification
+    let problems = verify::verify_wheel(wheel).map_err(Error::usage)?;
+    if !problems.is_empty() {
+        let mut message = format!("{} has {} problem(s):", wheel.display(), problems.len());
+        for problem in &problems {
+            message += &format!("\n  {}", problem);
+        }
+        return Err(Error::verify(failure::err_msg(message)));
+    }
+
+    eprintln!("✔ {} is valid", wheel.display());
+    Ok(())
+}
+
+fn main() {
+    let opt = Opt::from_args();
+
+    let result = match opt {
+        Opt::WheelNames {
+            info,
+            expect_one,
+            format,
+        } => wheel_names(info, expect_one, &format),
+        Opt::Build { info, options } => build(info, &options),
+        Opt::Sdist { info, output_dir } => sdist(info, &output_dir),
+        Opt::NixExpr { info } => nix_expr(info),
+        Opt::Verify { wheel } => verify(&wheel),
+    };
+
+    if let Err(err) = result {
+        err.report();
+        process::exit(err.exit_code());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    const CARGO_TOML: &str = r#"
+        [package]
+        name = "hello-py"
+        version = "0.1.0"
+        authors = []
+
+        [lib]
+        crate-type = ["cdylib"]
+    "#;
+
+    /// Generates the nix expression for a pyo3 crate with the given extra arguments
+    fn nix_expr_with(args: &[&str]) -> Result<String> {
+        let dir = TempDir::new().unwrap();
+        let manifest_path = dir.path().join("Cargo.toml");
+        fs::write(&manifest_path, CARGO_TOML).unwrap();
+
+        let mut all_args = vec![
+            "nix-expr",
+            "--module-name",
+            "hello",
+            "--manifest-path",
+            manifest_path.to_str().unwrap(),
+            "--bindings",
+            "pyo3",
+        ];
+        all_args.extend(args);
+        generate_nix_expr(&Info::from_iter_safe(&all_args).unwrap())
+    }
+
+    fn assert_passed_on(args: &[&str], expected: &str) {
+        let expr = nix_expr_with(args).unwrap();
+        assert!(
+            expr.contains(&format!("        {} \\\n", expected))
+                || expr.contains(&format!("        {}\n", expected)),
+            "{} is missing from\n{}",
+            expected,
+            expr
+        );
+    }
+
+    #[test]
+    fn test_nix_expr_set_version() {
+        assert_passed_on(
+            &["--set-version", "0.1.0.post1"],
+            "--set-version '0.1.0.post1'",
+        );
+        // The derivation has the version of the wheel
+        let expr = nix_expr_with(&["--set-version", "0.1.0.post1"]).unwrap();
+        assert_eq!(expr.matches("version = \"0.1.0.post1\";").count(), 2);
+    }
+
+    #[test]
+    fn test_nix_expr_compatibility() {
+        assert_passed_on(
+            &["--compatibility", "manylinux_2_17"],
+            "--compatibility 'manylinux_2_17'",
+        );
+    }
+
+    #[test]
+    fn test_nix_expr_entry_point() {
+        assert_passed_on(
+            &["--entry-point", "hello=hello:main"],
+            "--entry-point 'console_scripts:hello=hello:main'",
+        );
+        assert_passed_on(
+            &["--entry-point", "gui_scripts:app=hello:gui"],
+            "--entry-point 'gui_scripts:app=hello:gui'",
+        );
+    }
+
+    #[test]
+    fn test_nix_expr_summary() {
+        assert_passed_on(
+            &["--summary", "Greets ${USER}"],
+            "--summary 'Greets ''${USER}'",
+        );
+    }
+
+    #[test]
+    fn test_nix_expr_platform_tag() {
+        assert_passed_on(
+            &["--platform-tag", "manylinux2014_x86_64"],
+            "--platform-tag 'manylinux2014_x86_64'",
+        );
+    }
+
+    #[test]
+    fn test_nix_expr_requires_dist() {
+        let args = ["--requires-dist", "numpy>=1.16", "--requires-dist", "cffi"];
+        assert_passed_on(&args, "--requires-dist 'numpy>=1.16'");
+        assert_passed_on(&args, "--requires-dist 'cffi'");
+    }
+
+    #[test]
+    fn test_nix_expr_requires_python() {
+        assert_passed_on(&["--requires-python", ">=3.7"], "--requires-python '>=3.7'");
+    }
+
+    #[test]
+    fn test_nix_expr_classifier() {
+        assert_passed_on(
+            &["--classifier", "Programming Language :: Rust"],
+            "--classifier 'Programming Language :: Rust'",
+        );
+    }
+
+    #[test]
+    fn test_nix_expr_project_url() {
+        assert_passed_on(
+            &["--project-url", "Source, https://example.com/hello"],
+            "--project-url 'Source, https://example.com/hello'",
+        );
+    }
+
+    #[test]
+    fn test_nix_expr_python_version() {
+        assert_passed_on(&["--python-version", "3.9"], "--python-version '3.9'");
+    }
+
+    #[test]
+    fn test_nix_expr_rejects_interpreter_and_target() {
+        let interpreter = nix_expr_with(&["--interpreter", "/usr/bin/python3"]);
+        assert!(matches!(interpreter, Err(Error::Usage(_))));
+        let target = nix_expr_with(&["--target", "aarch64-unknown-linux-gnu"]);
+        assert!(matches!(target, Err(Error::Usage(_))));
+    }
+
+    fn info_with(args: &[&str]) -> Info {
+        let mut all_args = vec![
+            "build",
+            "--module-name",
+            "hello",
+            "--manifest-path",
+            "Cargo.toml",
+        ];
+        all_args.extend(args);
+        Info::from_iter_safe(&all_args).unwrap()
+    }
+
+    fn metadata_with_requirements(requires_dist: &[&str]) -> Metadata21 {
+        let cargo_toml: CargoToml = toml::from_str(CARGO_TOML).unwrap();
+        let mut metadata21 = Metadata21::from_cargo_toml(&cargo_toml, ".").unwrap();
+        metadata21.requires_dist = requires_dist.iter().map(ToString::to_string).collect();
+        metadata21
+    }
+
+    #[test]
+    fn test_requirement_name() {
+        assert_eq!(requirement_name("numpy"), "numpy");
+        assert_eq!(requirement_name("  NumPy>=1.16"), "numpy");
+        assert_eq!(requirement_name("Foo_Bar[x]>=1.0"), "foo-bar");
+        assert_eq!(
+            requirement_name("foo.bar ; python_version < '3.8'"),
+            "foo-bar"
+        );
+        assert_eq!(requirement_name("foo__-bar"), "foo-bar");
+        assert_eq!(requirement_name("foo (>=1.0)"), "foo");
+    }
+
+    #[test]
+    fn test_requires_dist_replaces_the_same_distribution() {
+        let info = info_with(&[
+            "--requires-dist",
+            "foo-bar>=2.0",
+            "--requires-dist",
+            "NumPy[extra]>=1.16",
+        ]);
+        let mut metadata21 = metadata_with_requirements(&["Foo_Bar>=1.0", "numpy", "cffi"]);
+        info.apply_overrides(&mut metadata21);
+        assert_eq!(
+            metadata21.requires_dist,
+            vec!["cffi", "foo-bar>=2.0", "NumPy[extra]>=1.16"]
+        );
+    }
+
+    #[test]
+    fn test_requires_dist_adds_new_distributions() {
+        let info = info_with(&["--requires-dist", "foo", "--requires-dist", "foo-bar"]);
+        let mut metadata21 = metadata_with_requirements(&["foobar"]);
+        info.apply_overrides(&mut metadata21);
+        assert_eq!(metadata21.requires_dist, vec!["foobar", "foo", "foo-bar"]);
+    }
+
+    #[test]
+    fn test_metadata_overrides() {
+        let info = info_with(&[
+            "--set-version",
+            "0.1.0.post1",
+            "--summary",
+            "Says hello",
+            "--requires-python",
+            ">=3.7",
+            "--classifier",
+            "Topic :: Utilities",
+            "--classifier",
+            "Topic :: Utilities",
+            "--project-url",
+            "Source, https://example.com/new",
+        ]);
+        let mut metadata21 = metadata_with_requirements(&[]);
+        metadata21.project_url = vec![
+            "Source, https://example.com/old".to_string(),
+            "Docs, https://example.com/docs".to_string(),
+        ];
+        info.apply_overrides(&mut metadata21);
+        assert_eq!(metadata21.version, "0.1.0.post1");
+        assert_eq!(metadata21.summary.as_deref(), Some("Says hello"));
+        assert_eq!(metadata21.requires_python.as_deref(), Some(">=3.7"));
+        assert_eq!(metadata21.classifier, vec!["Topic :: Utilities"]);
+        assert_eq!(
+            metadata21.project_url,
+            vec![
+                "Docs, https://example.com/docs",
+                "Source, https://example.com/new"
+            ]
+        );
     }
 }
@@ -0,0 +1,251 @@
+use crate::data::{rewrite_shebang, DataFiles};
+use crate::entry_points::{entry_points_txt, EntryPoints};
+use crate::stubs::Stubs;
+use failure::{bail, Error, ResultExt};
+use maturin::{Metadata21, ModuleWriter};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use walkdir::WalkDir;
+
+/// Copies the shared library into the wheel at the path of the extension module, e.g.
+/// `foo.cpython-37m-x86_64-linux-gnu.so` or `mypkg/_native.abi3.so`.
+pub fn write_bindings_module(
+    writer: &mut impl ModuleWriter,
+    library_path: &Path,
+    artifact: &Path,
+) -> Result<(), Error> {
+    writer.add_file(library_path, artifact)?;
+    Ok(())
+}
+
+/// Creates the cffi package with the shared library, the cffi declarations and the cffi loader.
+///
+/// The module path uses slashes instead of dots, e.g. `mypkg/_native`.
+pub fn write_cffi_module(
+    writer: &mut impl ModuleWriter,
+    crate_dir: &Path,
+    module_path: &str,
+    artifact: &Path,
+    python: &Path,
+) -> Result<(), Error> {
+    let cffi_declarations = generate_cffi_declarations(crate_dir, python)?;
+
+    let module = PathBuf::from(module_path);
+    writer.add_directory(&module)?;
+    writer.add_bytes(module.join("__init__.py"), cffi_init_file().as_bytes())?;
+    writer.add_bytes(module.join("ffi.py"), cffi_declarations.as_bytes())?;
+    writer.add_file(module.join("native.so"), artifact)?;
+
+    Ok(())
+}
+
+/// Adds the binary to the scripts directory of the wheel's data directory.
+pub fn write_bin(
+    writer: &mut impl ModuleWriter,
+    artifact: &Path,
+    metadata21: &Metadata21,
+) -> Result<(), Error> {
+    let bin_name = artifact
+        .file_name()
+        .ok_or_else(|| failure::err_msg("the artifact path has no file name"))?;
+
+    let data_dir = data_dir(metadata21).join("scripts");
+
+    writer.add_directory(&data_dir)?;
+
+    // add_file would lose the executable bit
+    let bytes = fs::read(artifact)?;
+    writer.add_bytes_with_permissions(data_dir.join(bin_name), &bytes, 0o755)?;
+    Ok(())
+}
+
+/// Adds the files of the schemes to the wheel's data directory. Scripts are made executable,
+/// with their python shebang replaced by `#!python`.
+pub fn write_data(
+    writer: &mut impl ModuleWriter,
+    metadata21: &Metadata21,
+    data_files: &DataFiles,
+) -> Result<(), Error> {
+    let data_dir = data_dir(metadata21);
+    for (scheme, files) in data_files {
+        let scheme_dir = data_dir.join(scheme);
+        for (target, source) in files {
+            if scheme == "scripts" {
+                let bytes = fs::read(source).context(format!("Can't read {}", source.display()))?;
+                writer.add_bytes_with_permissions(
+                    scheme_dir.join(target),
+                    &rewrite_shebang(&bytes),
+                    0o755,
+                )?;
+            } else {
+                writer
+                    .add_file(scheme_dir.join(target), source)
+                    .context(format!("Failed to add {}", source.display()))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the wheel's `<distribution>-<version>.data` directory
+fn data_dir(metadata21: &Metadata21) -> PathBuf {
+    PathBuf::from(format!(
+        "{}-{}.data",
+        metadata21.get_distribution_escaped(),
+        metadata21.get_version_escaped()
+    ))
+}
+
+/// Adds the python packages in the given directory to the wheel root.
+///
+/// Bytecode caches and native libraries left over from development builds are skipped, the latter
+/// so that they don't shadow the freshly built one.
+pub fn write_python_part(
+    writer: &mut impl ModuleWriter,
+    python_source: &Path,
+) -> Result<(), Error> {
+    let walk = WalkDir::new(python_source)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .filter_entry(|entry| entry.file_name() != "__pycache__");
+    for entry in walk {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(python_source)?;
+        if entry.file_type().is_dir() {
+            writer.add_directory(relative)?;
+            continue;
+        }
+
+        let extension = relative.extension().and_then(|ext| ext.to_str());
+        if let Some("pyc") | Some("so") | Some("pyd") = extension {
+            continue;
+        }
+        writer
+            .add_file(relative, entry.path())
+            .context(format!("Failed to add {}", entry.path().display()))?;
+    }
+
+    Ok(())
+}
+
+/// Adds the type stubs and the `py.typed` marker.
+pub fn write_stubs(writer: &mut impl ModuleWriter, stubs: &Stubs) -> Result<(), Error> {
+    for (target, source) in &stubs.files {
+        writer
+            .add_file(target, source)
+            .context(format!("Failed to add {}", source.display()))?;
+    }
+    if let Some(package) = &stubs.py_typed {
+        writer.add_bytes(package.join("py.typed"), b"")?;
+    }
+    Ok(())
+}
+
+/// Adds THIRD_PARTY_LICENSES to the .dist-info directory
+pub fn write_third_party_licenses(
+    writer: &mut impl ModuleWriter,
+    metadata21: &Metadata21,
+    text: &str,
+) -> Result<(), Error> {
+    let path = metadata21.get_dist_info_dir().join("THIRD_PARTY_LICENSES");
+    writer.add_bytes(path, text.as_bytes())?;
+    Ok(())
+}
+
+/// Adds entry_points.txt to the .dist-info directory, unless there are no entry points.
+///
+/// WheelWriter can only write console scripts by itself, so it always gets an empty map and
+/// all groups are written here instead.
+pub fn write_entry_points(
+    writer: &mut impl ModuleWriter,
+    metadata21: &Metadata21,
+    entry_points: &EntryPoints,
+) -> Result<(), Error> {
+    let text = entry_points_txt(entry_points);
+    if !text.is_empty() {
+        let path = metadata21.get_dist_info_dir().join("entry_points.txt");
+        writer.add_bytes(path, text.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Glue code that exposes `lib`.
+fn cffi_init_file() -> &'static str {
+    r#"__all__ = ["lib", "ffi"]
+
+import os
+from .ffi import ffi
+
+lib = ffi.dlopen(os.path.join(os.path.dirname(__file__), 'native.so'), 4098)
+del os
+"#
+}
+
+/// Returns the content of ffi.py, generated by running cbindgen and the cffi recompiler.
+///
+/// A header at `target/header.h` next to the manifest takes precedence over cbindgen, which is
+/// useful inside nix where the header is usually generated by an earlier build step.
+fn generate_cffi_declarations(crate_dir: &Path, python: &Path) -> Result<String, Error> {
+    let tempdir = tempfile::tempdir()?;
+    let maybe_header = crate_dir.join("target").join("header.h");
+
+    let header = if maybe_header.is_file() {
+        maybe_header
+    } else {
+        let mut config = cbindgen::Config::from_root_or_default(crate_dir);
+        config.defines = HashMap::new();
+        config.include_guard = None;
+
+        let bindings = cbindgen::Builder::new()
+            .with_config(config)
+            .with_crate(crate_dir)
+            .with_language(cbindgen::Language::C)
+            .with_no_includes()
+            .generate()
+            .context("Failed to run cbindgen")?;
+
+        let header = tempdir.path().join("header.h");
+        bindings.write_to_file(&header);
+        header
+    };
+
+    let ffi_py = tempdir.path().join("ffi.py");
+
+    // Raw strings so that windows paths don't turn into unicode escapes
+    let cffi_invocation = format!(
+        r#"
+import cffi
+from cffi import recompiler
+
+ffi = cffi.FFI()
+with open(r"{header}") as header:
+    ffi.cdef(header.read())
+recompiler.make_py_source(ffi, "ffi", r"{ffi_py}")
+"#,
+        ffi_py = ffi_py.display(),
+        header = header.display(),
+    );
+
+    let output = Command::new(python)
+        .args(["-c", &cffi_invocation])
+        .output()?;
+    if !output.status.success() {
+        bail!(
+            "Failed to generate cffi declarations using {}: {}\n--- Stdout:\n{}\n--- Stderr:\n{}",
+            python.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr),
+        );
+    }
+
+    // Don't swallow warnings
+    std::io::stderr().write_all(&output.stderr)?;
+
+    let ffi_py_content = fs::read_to_string(ffi_py)?;
+    tempdir.close()?;
+    Ok(ffi_py_content)
+}
@@ -0,0 +1,163 @@
+//! Generates a nix expression that builds the crate with `buildRustPackage` and installs the
+//! resulting wheel with `buildPythonPackage`
+
+use maturin::{BridgeModel, Metadata21};
+
+/// What the generated expression needs to know about the crate besides its metadata
+pub struct NixExprOptions<'a> {
+    pub module_name: &'a str,
+    pub bridge: &'a BridgeModel,
+    /// The name of the library target, e.g. "foo" for libfoo.so, or of the binary for bin
+    pub artifact_name: &'a str,
+    /// Whether --bindings was given explicitly and has to be passed on
+    pub bindings: Option<&'a str>,
+    pub abi3: Option<(usize, usize)>,
+    /// The other options of `maturin-nix build` that are passed on, as flag and value
+    pub extra_args: &'a [(&'a str, String)],
+}
+
+/// Quotes a string for nix, escaping everything that would otherwise be interpreted
+fn nix_string(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+/// Escapes a value for a shell command inside an indented nix string
+fn shell_word(value: &str) -> String {
+    let quoted = format!("'{}'", value.replace('\'', "'\\''"));
+    // '' and ${ are special in indented strings
+    quoted.replace("''", "'''").replace("${", "''${")
+}
+
+/// Translates an SPDX license expression from Cargo.toml into nixpkgs licenses.
+///
+/// Compound expressions are flattened into a list, which is how nixpkgs describes dual licensing.
+fn nix_license(license: &str) -> String {
+    let ids: Vec<String> = license
+        .split(['/', '(', ')'])
+        .flat_map(|part| part.split(" OR "))
+        .flat_map(|part| part.split(" AND "))
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| format!("lib.getLicenseFromSpdxId {}", nix_string(id)))
+        .collect();
+
+    match ids.as_slice() {
+        [id] => id.clone(),
+        _ => {
+            let ids: Vec<String> = ids.iter().map(|id| format!("({})", id)).collect();
+            format!("[ {} ]", ids.join(" "))
+        }
+    }
+}
+
+/// Returns the expression, which is a function to be called with `callPackage` from a python
+/// package set, i.e. `python3Packages.callPackage ./default.nix { }`. maturin-nix itself has to
+/// be in scope for `callPackage` as well.
+///
+/// The expression is meant to be saved next to Cargo.toml and Cargo.lock, which it uses as `src`.
+pub fn nix_expression(metadata21: &Metadata21, options: &NixExprOptions) -> String {
+    let pname = nix_string(&metadata21.name);
+    let version = nix_string(&metadata21.version);
+
+    let artifact = match options.bridge {
+        BridgeModel::Bin => shell_word(options.artifact_name),
+        _ => format!(
+            "{}${{stdenv.hostPlatform.extensions.sharedLibrary}}",
+            shell_word(&format!("lib{}", options.artifact_name))
+        ),
+    };
+
+    let mut build_args = vec![
+        format!("--module-name {}", shell_word(options.module_name)),
+        "--manifest-path Cargo.toml".to_string(),
+        "--artifact-path \"$artifact\"".to_string(),
+        "--output-dir $out".to_string(),
+        // Tags the wheel for the platform it runs on when cross-compiling with pkgsCross
+        "--target ${stdenv.hostPlatform.rust.rustcTarget}".to_string(),
+    ];
+    if let Some(bindings) = options.bindings {
+        build_args.push(format!("--bindings {}", shell_word(bindings)));
+    }
+    match (options.bridge, options.abi3) {
+        (BridgeModel::Bin, _) => {}
+        (_, Some((major, minor))) => build_args.push(format!("--abi3 {}.{}", major, minor)),
+        // The interpreter has to run on the build machine, which differs when cross-compiling
+        (BridgeModel::Cffi, None) => build_args.push(
+            "--interpreter ${python.pythonOnBuildForHost.withPackages (ps: [ ps.cffi ])}/bin/python"
+                .to_string(),
+        ),
+        (BridgeModel::Bindings(_), None) => build_args
+            .push("--interpreter ${python.pythonOnBuildForHost.interpreter}".to_string()),
+    }
+    for (flag, value) in options.extra_args {
+        build_args.push(format!("{} {}", flag, shell_word(value)));
+    }
+
+    let mut expr = format!(
+        r#"# Generated by maturin-nix nix-expr
+{{ lib, stdenv, buildPythonPackage, python, rustPlatform, maturin-nix }}:
+
+let
+  wheel = rustPlatform.buildRustPackage {{
+    pname = {pname_wheel};
+    version = {version};
+
+    src = ./.;
+    cargoLock.lockFile = ./Cargo.lock;
+
+    nativeBuildInputs = [ maturin-nix ];
+
+    installPhase = ''
+      runHook preInstall
+
+      mkdir -p $out
+      artifact=$(find target -path '*/release/*' -name {artifact} | head -n 1)
+      maturin-nix build \
+        {build_args}
+
+      runHook postInstall
+    '';
+  }};
+in
+buildPythonPackage {{
+  pname = {pname};
+  version = {version};
+  format = "wheel";
+
+  src = wheel;
+  unpackPhase = ''
+    mkdir -p dist
+    cp ${{wheel}}/*.whl dist/
+  '';
+"#,
+        pname_wheel = nix_string(&format!("{}-wheel", metadata21.name)),
+        pname = pname,
+        version = version,
+        artifact = artifact,
+        build_args = build_args.join(" \\\n        "),
+    );
+
+    if *options.bridge != BridgeModel::Bin {
+        let top_level = options.module_name.split('.').next().unwrap();
+        expr += &format!("\n  pythonImportsCheck = [ {} ];\n", nix_string(top_level));
+    }
+
+    expr += "\n  meta = {\n";
+    if let Some(summary) = &metadata21.summary {
+        expr += &format!("    description = {};\n", nix_string(summary));
+    }
+    if let Some(home_page) = &metadata21.home_page {
+        expr += &format!("    homepage = {};\n", nix_string(home_page));
+    }
+    if let Some(license) = &metadata21.license {
+        expr += &format!("    license = {};\n", nix_license(license));
+    }
+    expr += "  };\n}\n";
+
+    expr
+}
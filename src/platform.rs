@@ -0,0 +1,638 @@
+//! The platform the wheel is built for, which is not necessarily the one maturin-nix runs on.
+//!
+//! maturin's Target only knows x86 and x86_64 and is derived from the machine it runs on, which
+//! gives cross-compiled wheels the tag of the build machine. This is parsed from the rust target
+//! triple instead and decides the platform tag, the suffix of the native module and the machine
+//! type the artifact must have.
+
+use failure::{bail, Error, ResultExt};
+use goblin::mach::cputype::{CPU_TYPE_ARM64, CPU_TYPE_X86_64};
+use goblin::pe::header::{COFF_MACHINE_X86, COFF_MACHINE_X86_64};
+use maturin::{Manylinux, PythonInterpreter, Target};
+use std::env::consts;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Arch {
+    X86,
+    X86_64,
+    Aarch64,
+    Armv6,
+    Armv7,
+    Powerpc64,
+    Powerpc64le,
+    S390x,
+    Riscv64,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Os {
+    Linux,
+    Macos,
+    Windows,
+    FreeBSD,
+}
+
+/// The machine type in the header of an artifact, in the format the platform uses
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Machine {
+    Elf(u16),
+    MachO(u32),
+    Pe(u16),
+}
+
+/// goblin 0.0.24 doesn't know arm64 windows
+const COFF_MACHINE_ARM64: u16 = 0xaa64;
+
+/// The systems a linux wheel promises to work on
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Compatibility {
+    /// manylinux_X_Y from PEP 600: any system with glibc X.Y or later
+    Manylinux(u32, u32),
+    /// musllinux_X_Y from PEP 656: any system with musl X.Y or later
+    Musllinux(u32, u32),
+    /// linux: only systems like the one it was built on, which pip won't install from an index
+    Linux,
+}
+
+/// Parses "manylinux_2_17", "musllinux_1_2" or "linux"
+pub fn parse_compatibility(compatibility: &str) -> Result<Compatibility, String> {
+    if compatibility == "linux" {
+        return Ok(Compatibility::Linux);
+    }
+    let parts: Vec<&str> = compatibility.split('_').collect();
+    let version = match parts.as_slice() {
+        [_, major, minor] => major.parse().ok().zip(minor.parse().ok()),
+        _ => None,
+    };
+    match (parts[0], version) {
+        ("manylinux", Some((major, minor))) => Ok(Compatibility::Manylinux(major, minor)),
+        ("musllinux", Some((major, minor))) => Ok(Compatibility::Musllinux(major, minor)),
+        _ => Err(format!(
+            "expected manylinux_X_Y, musllinux_X_Y or linux, got {}",
+            compatibility
+        )),
+    }
+}
+
+impl fmt::Display for Compatibility {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compatibility::Manylinux(major, minor) => write!(f, "manylinux_{}_{}", major, minor),
+            Compatibility::Musllinux(major, minor) => write!(f, "musllinux_{}_{}", major, minor),
+            Compatibility::Linux => write!(f, "linux"),
+        }
+    }
+}
+
+/// The libraries every manylinux system has, from the manylinux policies of auditwheel
+const MANYLINUX_LIBRARIES: &[&str] = &[
+    "libc.so.6",
+    "libm.so.6",
+    "libdl.so.2",
+    "librt.so.1",
+    "libpthread.so.0",
+    "libutil.so.1",
+    "libnsl.so.1",
+    "libresolv.so.2",
+    "libcrypt.so.1",
+    "libgcc_s.so.1",
+    "libstdc++.so.6",
+    "libX11.so.6",
+    "libXext.so.6",
+    "libXrender.so.1",
+    "libICE.so.6",
+    "libSM.so.6",
+    "libGL.so.1",
+    "libgobject-2.0.so.0",
+    "libgthread-2.0.so.0",
+    "libglib-2.0.so.0",
+];
+
+impl Compatibility {
+    /// Whether every system the wheel promises to work on has the library, so that the wheel may
+    /// link against it without bundling it.
+    ///
+    /// musllinux only promises musl itself. Plain linux promises nothing, but the libraries of
+    /// either libc are still taken from the system rather than bundled. libpython is left to the
+    /// interpreter that loads the module.
+    pub fn is_system_library(self, name: &str) -> bool {
+        let is_glibc = MANYLINUX_LIBRARIES.contains(&name)
+            || name.starts_with("ld-linux")
+            || name.starts_with("ld64.so");
+        let is_musl =
+            name == "libc.so" || name.starts_with("ld-musl-") || name.starts_with("libc.musl-");
+        let is_python = name.starts_with("libpython");
+        match self {
+            Compatibility::Manylinux(..) => is_glibc || is_python,
+            Compatibility::Musllinux(..) => is_musl || is_python,
+            Compatibility::Linux => is_glibc || is_musl || is_python,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Platform {
+    arch: Arch,
+    os: Os,
+    /// The environment from the triple, e.g. "gnu", "musl" or "gnueabihf", which is part of the
+    /// multiarch tag on linux
+    env: String,
+    compatibility: Compatibility,
+    platform_tag: String,
+}
+
+impl Platform {
+    /// Parses a rust target triple like "aarch64-unknown-linux-gnu"
+    pub fn from_triple(triple: &str) -> Result<Platform, Error> {
+        let parts: Vec<&str> = triple.split('-').collect();
+        let arch = match parts[0] {
+            "x86_64" => Arch::X86_64,
+            "i386" | "i586" | "i686" => Arch::X86,
+            "aarch64" => Arch::Aarch64,
+            arm if arm.starts_with("armv7") || arm.starts_with("thumbv7") => Arch::Armv7,
+            arm if arm.starts_with("arm") => Arch::Armv6,
+            "powerpc64le" => Arch::Powerpc64le,
+            "powerpc64" => Arch::Powerpc64,
+            "s390x" => Arch::S390x,
+            "riscv64gc" | "riscv64" => Arch::Riscv64,
+            arch => bail!(
+                "The architecture {} of target {} is not supported",
+                arch,
+                triple
+            ),
+        };
+
+        let os_position = parts
+            .iter()
+            .position(|part| ["linux", "darwin", "windows", "freebsd"].contains(part));
+        let os = match os_position.map(|i| parts[i]) {
+            Some("linux") => Os::Linux,
+            Some("darwin") => Os::Macos,
+            Some("windows") => Os::Windows,
+            Some("freebsd") => Os::FreeBSD,
+            _ => bail!("The operating system of target {} is not supported", triple),
+        };
+        let env = os_position
+            .and_then(|i| parts.get(i + 1))
+            .map_or("gnu", |env| *env)
+            .to_string();
+
+        let platform =
+            Platform::new(arch, os, env).context(format!("Can't build wheels for {}", triple))?;
+        Ok(platform)
+    }
+
+    /// Returns the platform maturin-nix was compiled for, which is where it runs
+    pub fn current() -> Result<Platform, Error> {
+        let arch = match consts::ARCH {
+            "x86" => Arch::X86,
+            "x86_64" => Arch::X86_64,
+            "aarch64" => Arch::Aarch64,
+            "arm" => Arch::Armv7,
+            "powerpc64" if cfg!(target_endian = "little") => Arch::Powerpc64le,
+            "powerpc64" => Arch::Powerpc64,
+            "s390x" => Arch::S390x,
+            "riscv64" => Arch::Riscv64,
+            arch => bail!("The architecture {} is not supported", arch),
+        };
+        let os = match consts::OS {
+            "linux" => Os::Linux,
+            "macos" => Os::Macos,
+            "windows" => Os::Windows,
+            "freebsd" => Os::FreeBSD,
+            os => bail!("The operating system {} is not supported", os),
+        };
+        let env = match (cfg!(target_env = "musl"), arch) {
+            (true, Arch::Armv6) | (true, Arch::Armv7) => "musleabihf",
+            (true, _) => "musl",
+            (false, Arch::Armv6) | (false, Arch::Armv7) => "gnueabihf",
+            (false, _) => "gnu",
+        };
+        Platform::new(arch, os, env.to_string())
+    }
+
+    fn new(arch: Arch, os: Os, env: String) -> Result<Platform, Error> {
+        let platform_tag = match (os, arch) {
+            // manylinux basically says that there should be a bunch of standard libraries in
+            // standard places. This doesn't play nicely with nix so it's only used when asked for
+            // with `with_compatibility`.
+            (Os::Linux, arch) => format!("linux_{}", arch.python_name()),
+            (Os::Macos, Arch::X86_64) => "macosx_10_7_x86_64".to_string(),
+            (Os::Macos, Arch::Aarch64) => "macosx_11_0_arm64".to_string(),
+            (Os::Windows, Arch::X86) => "win32".to_string(),
+            (Os::Windows, Arch::X86_64) => "win_amd64".to_string(),
+            (Os::Windows, Arch::Aarch64) => "win_arm64".to_string(),
+            // The tag contains the release of the running system, which only maturin knows
+            (Os::FreeBSD, Arch::X86_64) if consts::OS == "freebsd" => {
+                Target::current().get_platform_tag(&Manylinux::Off)
+            }
+            (os, arch) => bail!("{:?} on {:?} is not supported", os, arch),
+        };
+        Ok(Platform {
+            arch,
+            os,
+            env,
+            compatibility: Compatibility::Linux,
+            platform_tag,
+        })
+    }
+
+    /// Tags the wheel as manylinux or musllinux, which needs the matching libc in the triple.
+    ///
+    /// Whether the artifact keeps that promise is up to `artifact::check_compatibility`.
+    pub fn with_compatibility(self, compatibility: Compatibility) -> Result<Platform, Error> {
+        if self.os != Os::Linux {
+            bail!(
+                "--compatibility only applies to linux, but the target is {:?}",
+                self.os
+            );
+        }
+        let platform_tag = match compatibility {
+            Compatibility::Manylinux(major, minor) if self.env.starts_with("gnu") => {
+                format!("manylinux_{}_{}_{}", major, minor, self.arch.python_name())
+            }
+            Compatibility::Musllinux(major, minor) if self.env.starts_with("musl") => {
+                format!("musllinux_{}_{}_{}", major, minor, self.arch.python_name())
+            }
+            Compatibility::Linux => format!("linux_{}", self.arch.python_name()),
+            _ => bail!(
+                "{} doesn't match the {} libc of the target",
+                compatibility,
+                self.env
+            ),
+        };
+        Ok(Platform {
+            compatibility,
+            platform_tag,
+            ..self
+        })
+    }
+
+    /// Replaces the platform tag, without any checks
+    pub fn with_platform_tag(self, platform_tag: String) -> Platform {
+        Platform {
+            platform_tag,
+            ..self
+        }
+    }
+
+    pub fn compatibility(&self) -> Compatibility {
+        self.compatibility
+    }
+
+    /// Whether the other platform has the same architecture, operating system and libc, no
+    /// matter how wheels for it are tagged
+    pub fn is_same_system(&self, other: &Platform) -> bool {
+        (self.arch, self.os, &self.env) == (other.arch, other.os, &other.env)
+    }
+
+    /// Returns the file name cargo gives a cdylib or binary of the given name
+    pub fn artifact_file_name(&self, name: &str, is_bin: bool) -> String {
+        match (self.os, is_bin) {
+            (Os::Windows, true) => format!("{}.exe", name),
+            (_, true) => name.to_string(),
+            (Os::Windows, false) => format!("{}.dll", name),
+            (Os::Macos, false) => format!("lib{}.dylib", name),
+            (_, false) => format!("lib{}.so", name),
+        }
+    }
+
+    pub fn is_macos(&self) -> bool {
+        self.os == Os::Macos
+    }
+
+    pub fn is_windows(&self) -> bool {
+        self.os == Os::Windows
+    }
+
+    /// Returns the platform part of the wheel tag, e.g. "linux_aarch64"
+    pub fn platform_tag(&self) -> &str {
+        &self.platform_tag
+    }
+
+    /// Returns the tag for the wheel name and the tags for the WHEEL file of wheels that work
+    /// with any python version
+    pub fn universal_tags(&self) -> (String, Vec<String>) {
+        let tag = format!("py2.py3-none-{}", self.platform_tag);
+        let tags = expand_tag(&tag);
+        (tag, tags)
+    }
+
+    /// Returns the tag of a wheel for the given interpreter.
+    ///
+    /// The interpreter runs on the build machine, so it only knows the python and abi tags.
+    pub fn interpreter_tag(&self, python: &PythonInterpreter) -> String {
+        let tag = python.get_tag(&Manylinux::Off);
+        let python_and_abi = tag
+            .rsplit_once('-')
+            .map_or(tag.as_str(), |(start, _)| start);
+        format!("{}-{}", python_and_abi, self.platform_tag)
+    }
+
+    /// Returns the file name of the native module for the given interpreter, e.g.
+    /// "foo.cpython-38-aarch64-linux-gnu.so"
+    pub fn library_name(&self, base: &str, python: &PythonInterpreter) -> String {
+        let host = match Platform::current() {
+            Ok(host) if !host.is_same_system(self) => host,
+            _ => return python.get_library_name(base),
+        };
+
+        match (python.interpreter.to_string().as_str(), &python.ext_suffix) {
+            // PyPy's suffix has the multiarch tag of the build machine, if any
+            ("PyPy", Some(ext_suffix)) => format!(
+                "{}{}",
+                base,
+                ext_suffix.replace(&host.multiarch(), &self.multiarch())
+            ),
+            _ if self.is_windows() => format!(
+                "{}.cp{}{}-{}.pyd",
+                base, python.major, python.minor, self.platform_tag
+            ),
+            _ if self.os == Os::Linux => format!(
+                "{}.cpython-{}{}{}-{}.so",
+                base,
+                python.major,
+                python.minor,
+                python.abiflags,
+                self.multiarch()
+            ),
+            _ => format!(
+                "{}.cpython-{}{}{}-darwin.so",
+                base, python.major, python.minor, python.abiflags
+            ),
+        }
+    }
+
+    /// Returns the file name of a stable abi native module
+    pub fn abi3_library_name(&self, base: &str) -> String {
+        if self.is_windows() {
+            format!("{}.pyd", base)
+        } else {
+            format!("{}.abi3.so", base)
+        }
+    }
+
+    /// Checks that interpreters from the build machine can describe native modules for this
+    /// platform, which only works if both have the same operating system
+    pub fn check_interpreters_usable(&self) -> Result<(), Error> {
+        let host = Platform::current()?;
+        if host.os != self.os {
+            bail!(
+                "Can't build for {} with the python interpreters of this {:?} machine, \
+                 use --abi3 instead",
+                self.platform_tag,
+                host.os
+            );
+        }
+        Ok(())
+    }
+
+    /// The multiarch tuple debian and CPython use for the platform, e.g. "aarch64-linux-gnu"
+    fn multiarch(&self) -> String {
+        let arch = match self.arch {
+            Arch::X86 => "i386",
+            Arch::X86_64 => "x86_64",
+            Arch::Aarch64 => "aarch64",
+            Arch::Armv6 | Arch::Armv7 => "arm",
+            Arch::Powerpc64 => "powerpc64",
+            Arch::Powerpc64le => "powerpc64le",
+            Arch::S390x => "s390x",
+            Arch::Riscv64 => "riscv64",
+        };
+        format!("{}-linux-{}", arch, self.env)
+    }
+
+    /// Returns the file format and machine type that artifacts for this platform have
+    pub fn machine(&self) -> Machine {
+        match (self.os, self.arch) {
+            (Os::Linux, arch) | (Os::FreeBSD, arch) => Machine::Elf(arch.elf_machine()),
+            (Os::Macos, Arch::Aarch64) => Machine::MachO(CPU_TYPE_ARM64),
+            (Os::Macos, _) => Machine::MachO(CPU_TYPE_X86_64),
+            (Os::Windows, Arch::X86) => Machine::Pe(COFF_MACHINE_X86),
+            (Os::Windows, Arch::Aarch64) => Machine::Pe(COFF_MACHINE_ARM64),
+            (Os::Windows, _) => Machine::Pe(COFF_MACHINE_X86_64),
+        }
+    }
+}
+
+/// Expands a compressed tag like "py2.py3-none-any" into the tags it stands for, which the WHEEL
+/// file lists one by one
+pub fn expand_tag(tag: &str) -> Vec<String> {
+    let parts: Vec<&str> = tag.splitn(3, '-').collect();
+    let (pythons, abis, platforms) = match parts.as_slice() {
+        [python, abi, platform] => (*python, *abi, *platform),
+        _ => return vec![tag.to_string()],
+    };
+    let mut tags = Vec::new();
+    for python in pythons.split('.') {
+        for abi in abis.split('.') {
+            for platform in platforms.split('.') {
+                tags.push(format!("{}-{}-{}", python, abi, platform));
+            }
+        }
+    }
+    tags
+}
+
+impl Arch {
+    /// The name python uses in platform tags, which is what `uname -m` says
+    fn python_name(self) -> &'static str {
+        match self {
+            Arch::X86 => "i686",
+            Arch::X86_64 => "x86_64",
+            Arch::Aarch64 => "aarch64",
+            Arch::Armv6 => "armv6l",
+            Arch::Armv7 => "armv7l",
+            Arch::Powerpc64 => "ppc64",
+            Arch::Powerpc64le => "ppc64le",
+            Arch::S390x => "s390x",
+            Arch::Riscv64 => "riscv64",
+        }
+    }
+
+    /// The e_machine value in the ELF header
+    fn elf_machine(self) -> u16 {
+        match self {
+            Arch::X86 => 3,
+            Arch::X86_64 => 62,
+            Arch::Aarch64 => 183,
+            Arch::Armv6 | Arch::Armv7 => 40,
+            Arch::Powerpc64 | Arch::Powerpc64le => 21,
+            Arch::S390x => 22,
+            Arch::Riscv64 => 243,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use maturin::BridgeModel;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    fn platform_tag(triple: &str) -> String {
+        Platform::from_triple(triple)
+            .unwrap()
+            .platform_tag()
+            .to_string()
+    }
+
+    /// Returns the interpreter maturin finds for a script that prints the given sysconfig, since
+    /// maturin doesn't export what it takes to create one directly
+    fn python(dir: &Path, interpreter: &str, minor: usize, abiflags: &str) -> PythonInterpreter {
+        let message = format!(
+            r#"{{"major": 3, "minor": {minor}, "abiflags": "{abiflags}", "interpreter": "{interpreter}", "ext_suffix": null, "m": false, "u": false, "d": false, "platform": "linux", "abi_tag": "73"}}"#,
+            minor = minor,
+            abiflags = abiflags,
+            interpreter = interpreter,
+        );
+        let path = dir.join(format!("{}3.{}", interpreter, minor));
+        fs::write(&path, format!("#!/bin/sh\necho '{}'\n", message)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        PythonInterpreter::check_executable(&path, &Target::current(), &BridgeModel::Cffi)
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_linux_triples() {
+        assert_eq!(platform_tag("x86_64-unknown-linux-gnu"), "linux_x86_64");
+        assert_eq!(platform_tag("aarch64-unknown-linux-gnu"), "linux_aarch64");
+        assert_eq!(
+            platform_tag("armv7-unknown-linux-gnueabihf"),
+            "linux_armv7l"
+        );
+        assert_eq!(platform_tag("arm-unknown-linux-gnueabihf"), "linux_armv6l");
+        assert_eq!(platform_tag("i686-unknown-linux-gnu"), "linux_i686");
+        assert_eq!(platform_tag("x86_64-unknown-linux-musl"), "linux_x86_64");
+
+        let armv7 = Platform::from_triple("armv7-unknown-linux-gnueabihf").unwrap();
+        assert_eq!(armv7.multiarch(), "arm-linux-gnueabihf");
+        let i686 = Platform::from_triple("i686-unknown-linux-gnu").unwrap();
+        assert_eq!(i686.multiarch(), "i386-linux-gnu");
+        assert_eq!(i686.machine(), Machine::Elf(3));
+    }
+
+    #[test]
+    fn test_compatibility_needs_the_matching_libc() {
+        let musl = Platform::from_triple("x86_64-unknown-linux-musl").unwrap();
+        let musllinux = musl
+            .clone()
+            .with_compatibility(Compatibility::Musllinux(1, 2))
+            .unwrap();
+        assert_eq!(musllinux.platform_tag(), "musllinux_1_2_x86_64");
+        assert!(musl
+            .with_compatibility(Compatibility::Manylinux(2, 17))
+            .is_err());
+
+        let gnu = Platform::from_triple("aarch64-unknown-linux-gnu").unwrap();
+        let manylinux = gnu
+            .clone()
+            .with_compatibility(Compatibility::Manylinux(2, 17))
+            .unwrap();
+        assert_eq!(manylinux.platform_tag(), "manylinux_2_17_aarch64");
+        assert!(gnu
+            .with_compatibility(Compatibility::Musllinux(1, 2))
+            .is_err());
+
+        let darwin = Platform::from_triple("aarch64-apple-darwin").unwrap();
+        assert!(darwin.with_compatibility(Compatibility::Linux).is_err());
+    }
+
+    #[test]
+    fn test_darwin_triples() {
+        assert_eq!(platform_tag("x86_64-apple-darwin"), "macosx_10_7_x86_64");
+        assert_eq!(platform_tag("aarch64-apple-darwin"), "macosx_11_0_arm64");
+
+        let darwin = Platform::from_triple("aarch64-apple-darwin").unwrap();
+        assert!(darwin.is_macos());
+        assert_eq!(darwin.artifact_file_name("hello", false), "libhello.dylib");
+        assert_eq!(darwin.machine(), Machine::MachO(CPU_TYPE_ARM64));
+    }
+
+    #[test]
+    fn test_windows_triples() {
+        assert_eq!(platform_tag("x86_64-pc-windows-msvc"), "win_amd64");
+        assert_eq!(platform_tag("i686-pc-windows-msvc"), "win32");
+        assert_eq!(platform_tag("aarch64-pc-windows-msvc"), "win_arm64");
+
+        let windows = Platform::from_triple("x86_64-pc-windows-gnu").unwrap();
+        assert!(windows.is_windows());
+        assert_eq!(windows.artifact_file_name("hello", false), "hello.dll");
+        assert_eq!(windows.artifact_file_name("hello", true), "hello.exe");
+        assert_eq!(windows.abi3_library_name("hello"), "hello.pyd");
+    }
+
+    #[test]
+    fn test_unsupported_triples() {
+        assert!(Platform::from_triple("mips-unknown-linux-gnu").is_err());
+        assert!(Platform::from_triple("x86_64-unknown-netbsd").is_err());
+        assert!(Platform::from_triple("armv7-apple-darwin").is_err());
+    }
+
+    #[test]
+    fn test_expand_tag() {
+        assert_eq!(
+            expand_tag("py2.py3-none-any"),
+            vec!["py2-none-any", "py3-none-any"]
+        );
+        assert_eq!(
+            expand_tag("cp39-abi3-manylinux_2_17_x86_64.manylinux2014_x86_64"),
+            vec![
+                "cp39-abi3-manylinux_2_17_x86_64",
+                "cp39-abi3-manylinux2014_x86_64"
+            ]
+        );
+        assert_eq!(
+            expand_tag("cp39-cp39-linux_x86_64"),
+            vec!["cp39-cp39-linux_x86_64"]
+        );
+        assert_eq!(expand_tag("invalid"), vec!["invalid"]);
+    }
+
+    #[test]
+    fn test_interpreter_tag() {
+        let dir = TempDir::new().unwrap();
+        // The platform comes from the target, not from the interpreter
+        let aarch64 = Platform::from_triple("aarch64-unknown-linux-gnu").unwrap();
+        assert_eq!(
+            aarch64.interpreter_tag(&python(dir.path(), "cpython", 9, "")),
+            "cp39-cp39-linux_aarch64"
+        );
+        assert_eq!(
+            aarch64.interpreter_tag(&python(dir.path(), "cpython", 7, "m")),
+            "cp37-cp37m-linux_aarch64"
+        );
+        assert_eq!(
+            aarch64.interpreter_tag(&python(dir.path(), "pypy", 7, "")),
+            "pp373-pypy3_73-linux_aarch64"
+        );
+
+        let manylinux = aarch64
+            .with_compatibility(Compatibility::Manylinux(2, 17))
+            .unwrap();
+        assert_eq!(
+            manylinux.interpreter_tag(&python(dir.path(), "cpython", 9, "")),
+            "cp39-cp39-manylinux_2_17_aarch64"
+        );
+    }
+
+    #[test]
+    fn test_universal_tags() {
+        let platform = Platform::from_triple("x86_64-pc-windows-msvc").unwrap();
+        assert_eq!(
+            platform.universal_tags(),
+            (
+                "py2.py3-none-win_amd64".to_string(),
+                vec![
+                    "py2-none-win_amd64".to_string(),
+                    "py3-none-win_amd64".to_string()
+                ]
+            )
+        );
+    }
+}
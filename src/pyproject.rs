@@ -0,0 +1,550 @@
+//! The `[project]` table of pyproject.toml as specified in PEP 621
+//!
+//! Cargo.toml can't express most python metadata, so the `[project]` table next to it takes
+//! precedence: every field it sets replaces what was derived from Cargo.toml, lists included,
+//! and everything else keeps its value from Cargo.toml. Fields that can only come from Cargo.toml
+//! should be listed in `dynamic`, which PEP 621 forbids for fields that are also set.
+
+use failure::{bail, Error, ResultExt};
+use maturin::Metadata21;
+use std::fs;
+use std::path::Path;
+
+/// The fields PEP 621 defines, which are the only ones allowed in `[project]`
+const FIELDS: &[&str] = &[
+    "name",
+    "version",
+    "description",
+    "readme",
+    "requires-python",
+    "license",
+    "authors",
+    "maintainers",
+    "keywords",
+    "classifiers",
+    "urls",
+    "scripts",
+    "gui-scripts",
+    "entry-points",
+    "dependencies",
+    "optional-dependencies",
+    "dynamic",
+];
+
+/// Reads the pyproject.toml in the given directory, if there is one
+pub fn read(project_root: &Path) -> Result<Option<toml::Value>, Error> {
+    let path = project_root.join("pyproject.toml");
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(&path).context(format!("Can't read {}", path.display()))?;
+    let pyproject =
+        toml::from_str(&contents).context(format!("Failed to parse {}", path.display()))?;
+    Ok(Some(pyproject))
+}
+
+/// Merges the `[project]` table of pyproject.toml, if there is one, over the metadata from
+/// Cargo.toml. Files referenced from the table are relative to the project root.
+pub fn apply_project_table(
+    metadata21: &mut Metadata21,
+    pyproject: Option<&toml::Value>,
+    project_root: &Path,
+) -> Result<(), Error> {
+    let project = match pyproject.and_then(|pyproject| pyproject.get("project")) {
+        Some(project) => project,
+        None => return Ok(()),
+    };
+    apply(metadata21, project, project_root)
+        .context("Invalid [project] table in pyproject.toml")?;
+    Ok(())
+}
+
+fn apply(
+    metadata21: &mut Metadata21,
+    project: &toml::Value,
+    project_root: &Path,
+) -> Result<(), Error> {
+    let project = match project.as_table() {
+        Some(project) => project,
+        None => bail!("[project] must be a table"),
+    };
+    for key in project.keys() {
+        if !FIELDS.contains(&key.as_str()) {
+            bail!("unknown field {}", key);
+        }
+    }
+
+    for field in string_array(project, "dynamic")?.unwrap_or_default() {
+        if field == "name" {
+            bail!("name can't be dynamic");
+        }
+        if !FIELDS.contains(&field.as_str()) || field == "dynamic" {
+            bail!("dynamic lists unknown field {}", field);
+        }
+        if project.contains_key(&field) {
+            bail!("{} is listed in dynamic, but also set", field);
+        }
+    }
+
+    if let Some(name) = string(project, "name")? {
+        metadata21.name = name;
+    }
+    if let Some(version) = string(project, "version")? {
+        metadata21.version = version;
+    }
+    if let Some(description) = string(project, "description")? {
+        if description.contains('\n') {
+            bail!("description must be a single line, use readme for longer descriptions");
+        }
+        metadata21.summary = Some(description);
+    }
+    if let Some(readme) = project.get("readme") {
+        let (description, content_type) = readme_field(readme, project_root)?;
+        metadata21.description = Some(description);
+        metadata21.description_content_type = Some(content_type);
+    }
+    if let Some(requires_python) = string(project, "requires-python")? {
+        metadata21.requires_python = Some(requires_python);
+    }
+    if let Some(license) = project.get("license") {
+        metadata21.license = license_field(license)?;
+    }
+    if let Some(authors) = project.get("authors") {
+        let (names, emails) = people_field(authors, "authors")?;
+        metadata21.author = names;
+        metadata21.author_email = emails;
+    }
+    if let Some(maintainers) = project.get("maintainers") {
+        let (names, emails) = people_field(maintainers, "maintainers")?;
+        metadata21.maintainer = names;
+        metadata21.maintainer_email = emails;
+    }
+    if let Some(keywords) = string_array(project, "keywords")? {
+        metadata21.keywords = Some(keywords.join(","));
+    }
+    if let Some(classifiers) = string_array(project, "classifiers")? {
+        metadata21.classifier = classifiers;
+    }
+    if let Some(urls) = project.get("urls") {
+        metadata21.project_url = urls_field(urls)?;
+    }
+    if let Some(dependencies) = string_array(project, "dependencies")? {
+        metadata21.requires_dist = dependencies;
+    }
+    if let Some(optional) = project.get("optional-dependencies") {
+        let optional = match optional.as_table() {
+            Some(optional) => optional,
+            None => bail!("optional-dependencies must be a table"),
+        };
+        metadata21.provides_extra.clear();
+        for (extra, requirements) in optional {
+            let key = format!("optional-dependencies.{}", extra);
+            let requirements = match requirements.as_array() {
+                Some(requirements) => requirements,
+                None => bail!("{} must be a list of strings", key),
+            };
+            metadata21.provides_extra.push(extra.clone());
+            for requirement in requirements {
+                let requirement = match requirement.as_str() {
+                    Some(requirement) => requirement,
+                    None => bail!("{} must be a list of strings", key),
+                };
+                metadata21
+                    .requires_dist
+                    .push(with_extra_marker(requirement, extra));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Makes a requirement conditional on the extra, keeping the marker it already has
+fn with_extra_marker(requirement: &str, extra: &str) -> String {
+    match requirement.find(';') {
+        Some(i) => format!(
+            "{}; ({}) and extra == \"{}\"",
+            requirement[..i].trim(),
+            requirement[i + 1..].trim(),
+            extra
+        ),
+        None => format!("{}; extra == \"{}\"", requirement.trim(), extra),
+    }
+}
+
+fn string(project: &toml::value::Table, key: &str) -> Result<Option<String>, Error> {
+    match project.get(key) {
+        None => Ok(None),
+        Some(toml::Value::String(value)) => Ok(Some(value.clone())),
+        Some(_) => bail!("{} must be a string", key),
+    }
+}
+
+fn string_array(project: &toml::value::Table, key: &str) -> Result<Option<Vec<String>>, Error> {
+    let values = match project.get(key) {
+        None => return Ok(None),
+        Some(toml::Value::Array(values)) => values,
+        Some(_) => bail!("{} must be a list of strings", key),
+    };
+    let mut strings = Vec::new();
+    for value in values {
+        match value.as_str() {
+            Some(value) => strings.push(value.to_string()),
+            None => bail!("{} must be a list of strings", key),
+        }
+    }
+    Ok(Some(strings))
+}
+
+/// Reads the readme, which is either a path or a table with a `file` or the `text` and a
+/// `content-type`, which for a path is guessed from the extension
+fn readme_field(readme: &toml::Value, project_root: &Path) -> Result<(String, String), Error> {
+    let (file, text, content_type) = match readme {
+        toml::Value::String(file) => (Some(file.as_str()), None, None),
+        toml::Value::Table(table) => {
+            for key in table.keys() {
+                if !["file", "text", "content-type"].contains(&key.as_str()) {
+                    bail!("unknown field {} in readme", key);
+                }
+            }
+            let field = |key| match table.get(key) {
+                None => Ok(None),
+                Some(toml::Value::String(value)) => Ok(Some(value.as_str())),
+                Some(_) => Err(failure::format_err!("readme.{} must be a string", key)),
+            };
+            (field("file")?, field("text")?, field("content-type")?)
+        }
+        _ => bail!("readme must be a string or a table"),
+    };
+
+    let description = match (file, text) {
+        (Some(file), None) => {
+            let path = project_root.join(file);
+            fs::read_to_string(&path).context(format!("Can't read readme {}", path.display()))?
+        }
+        (None, Some(text)) => text.to_string(),
+        _ => bail!("readme must have either file or text"),
+    };
+
+    let content_type = match (content_type, file) {
+        (Some(content_type), _) => content_type.to_string(),
+        (None, Some(file)) if file.to_lowercase().ends_with(".md") => "text/markdown".to_string(),
+        (None, Some(file)) if file.to_lowercase().ends_with(".rst") => "text/x-rst".to_string(),
+        (None, Some(file)) if file.to_lowercase().ends_with(".txt") => "text/plain".to_string(),
+        _ => bail!("readme needs a content-type"),
+    };
+
+    Ok((description, content_type))
+}
+
+/// The forms of `license` in `[project]`
+enum License<'a> {
+    Expression(&'a str),
+    File(&'a str),
+    Text(&'a str),
+}
+
+fn parse_license(license: &toml::Value) -> Result<License<'_>, Error> {
+    let table = match license {
+        toml::Value::String(expression) => return Ok(License::Expression(expression)),
+        toml::Value::Table(table) => table,
+        _ => bail!("license must be a string or a table"),
+    };
+    for key in table.keys() {
+        if key != "file" && key != "text" {
+            bail!("unknown field {} in license", key);
+        }
+    }
+    match (table.get("file"), table.get("text")) {
+        (Some(toml::Value::String(file)), None) => Ok(License::File(file)),
+        (None, Some(toml::Value::String(text))) => Ok(License::Text(text)),
+        _ => bail!("license must have either file or text as a string"),
+    }
+}
+
+/// Reads the license for the License field.
+///
+/// A file becomes a License-File instead, see `license_file`, rather than a License that would
+/// have to hold the whole text. Multi-line text is folded into continuation lines, since a blank
+/// line would end the headers of METADATA.
+fn license_field(license: &toml::Value) -> Result<Option<String>, Error> {
+    match parse_license(license)? {
+        License::Expression(expression) => Ok(Some(fold(expression))),
+        License::File(_) => Ok(None),
+        License::Text(text) => Ok(Some(fold(text))),
+    }
+}
+
+/// Indents every line after the first like the continuation lines of email headers, which keeps
+/// blank lines from ending the headers
+fn fold(text: &str) -> String {
+    text.trim_end()
+        .lines()
+        .map(str::trim_end)
+        .collect::<Vec<_>>()
+        .join("\n        ")
+}
+
+/// Returns the `file` of `license = { file = "..." }` in the `[project]` table of pyproject.toml,
+/// which goes into `.dist-info/licenses` like the `license-file` of Cargo.toml and takes
+/// precedence over it
+pub fn license_file(pyproject: Option<&toml::Value>) -> Result<Option<String>, Error> {
+    let license = match pyproject
+        .and_then(|pyproject| pyproject.get("project"))
+        .and_then(|project| project.get("license"))
+    {
+        Some(license) => license,
+        None => return Ok(None),
+    };
+    let license = parse_license(license).context("Invalid [project] table in pyproject.toml")?;
+    match license {
+        License::File(file) => Ok(Some(file.to_string())),
+        _ => Ok(None),
+    }
+}
+
+/// Splits authors or maintainers into the name and email fields of core metadata: people with
+/// only a name go to the first, everyone with an email to the second as `name <email>`
+fn people_field(
+    people: &toml::Value,
+    key: &str,
+) -> Result<(Option<String>, Option<String>), Error> {
+    let people = match people.as_array() {
+        Some(people) => people,
+        None => bail!("{} must be a list of tables", key),
+    };
+
+    let mut names = Vec::new();
+    let mut emails = Vec::new();
+    for person in people {
+        let person = match person.as_table() {
+            Some(person) => person,
+            None => bail!("{} must be a list of tables", key),
+        };
+        for field in person.keys() {
+            if field != "name" && field != "email" {
+                bail!("unknown field {} in {}", field, key);
+            }
+        }
+        let name = string(person, "name").context(format!("Invalid entry in {}", key))?;
+        let email = string(person, "email").context(format!("Invalid entry in {}", key))?;
+        match (name, email) {
+            (Some(name), None) => names.push(name),
+            (Some(name), Some(email)) => emails.push(format!("{} <{}>", name, email)),
+            (None, Some(email)) => emails.push(email),
+            (None, None) => bail!("entries in {} need a name or an email", key),
+        }
+    }
+
+    let join = |values: Vec<String>| Some(values.join(", ")).filter(|joined| !joined.is_empty());
+    Ok((join(names), join(emails)))
+}
+
+/// Converts the urls into `label, url` project urls
+fn urls_field(urls: &toml::Value) -> Result<Vec<String>, Error> {
+    let urls = match urls.as_table() {
+        Some(urls) => urls,
+        None => bail!("urls must be a table"),
+    };
+    let mut project_urls = Vec::new();
+    for (label, url) in urls {
+        match url.as_str() {
+            Some(url) => project_urls.push(format!("{}, {}", label, url)),
+            None => bail!("urls.{} must be a string", label),
+        }
+    }
+    Ok(project_urls)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use maturin::CargoToml;
+    use tempfile::TempDir;
+
+    const CARGO_TOML: &str = r#"
+        [package]
+        name = "hello-py"
+        version = "0.1.0"
+        authors = ["Jane Doe <jane@example.com>"]
+        description = "From Cargo.toml"
+        license = "MIT"
+        keywords = ["hello"]
+
+        [package.metadata.maturin]
+        classifier = ["Programming Language :: Rust"]
+        requires-dist = ["cffi"]
+    "#;
+
+    /// Applies the pyproject.toml over the metadata of `CARGO_TOML`, with the files in `dir`
+    fn metadata_with(pyproject: &str, dir: &Path) -> Result<Metadata21, Error> {
+        let cargo_toml: CargoToml = toml::from_str(CARGO_TOML).unwrap();
+        let mut metadata21 = Metadata21::from_cargo_toml(&cargo_toml, dir).unwrap();
+        let pyproject: toml::Value = toml::from_str(pyproject).unwrap();
+        apply_project_table(&mut metadata21, Some(&pyproject), dir)?;
+        Ok(metadata21)
+    }
+
+    fn metadata(pyproject: &str) -> Result<Metadata21, Error> {
+        metadata_with(pyproject, TempDir::new().unwrap().path())
+    }
+
+    #[test]
+    fn test_project_table_takes_precedence() {
+        let metadata21 = metadata(
+            r#"
+            [project]
+            name = "hello"
+            version = "1.0.0"
+            description = "From pyproject.toml"
+            classifiers = ["Topic :: Utilities"]
+            dependencies = ["numpy>=1.16"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(metadata21.name, "hello");
+        assert_eq!(metadata21.version, "1.0.0");
+        assert_eq!(metadata21.summary.as_deref(), Some("From pyproject.toml"));
+        // Lists are replaced rather than merged
+        assert_eq!(metadata21.classifier, vec!["Topic :: Utilities"]);
+        assert_eq!(metadata21.requires_dist, vec!["numpy>=1.16"]);
+        // Everything else keeps its value from Cargo.toml
+        assert_eq!(metadata21.license.as_deref(), Some("MIT"));
+        assert_eq!(metadata21.keywords.as_deref(), Some("hello"));
+        assert_eq!(
+            metadata21.author_email.as_deref(),
+            Some("Jane Doe <jane@example.com>")
+        );
+    }
+
+    #[test]
+    fn test_without_project_table() {
+        let metadata21 = metadata("[build-system]\nrequires = []\n").unwrap();
+        assert_eq!(metadata21.name, "hello-py");
+        assert_eq!(metadata21.summary.as_deref(), Some("From Cargo.toml"));
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        assert!(metadata("[project]\nhomepage = \"https://example.com\"\n").is_err());
+    }
+
+    #[test]
+    fn test_dynamic() {
+        let metadata21 = metadata("[project]\ndynamic = [\"version\"]\n").unwrap();
+        assert_eq!(metadata21.version, "0.1.0");
+
+        assert!(metadata("[project]\ndynamic = [\"version\"]\nversion = \"1.0.0\"\n").is_err());
+        assert!(metadata("[project]\ndynamic = [\"name\"]\n").is_err());
+        assert!(metadata("[project]\ndynamic = [\"homepage\"]\n").is_err());
+    }
+
+    #[test]
+    fn test_readme_forms() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("README.md"), "# Hello\n").unwrap();
+        fs::write(dir.path().join("README"), "Hello\n").unwrap();
+
+        let metadata21 = metadata_with("[project]\nreadme = \"README.md\"\n", dir.path()).unwrap();
+        assert_eq!(metadata21.description.as_deref(), Some("# Hello\n"));
+        assert_eq!(
+            metadata21.description_content_type.as_deref(),
+            Some("text/markdown")
+        );
+
+        let metadata21 = metadata_with(
+            "[project]\nreadme = { file = \"README\", content-type = \"text/plain\" }\n",
+            dir.path(),
+        )
+        .unwrap();
+        assert_eq!(metadata21.description.as_deref(), Some("Hello\n"));
+        assert_eq!(
+            metadata21.description_content_type.as_deref(),
+            Some("text/plain")
+        );
+
+        let metadata21 = metadata_with(
+            "[project]\nreadme = { text = \"Hi\", content-type = \"text/x-rst\" }\n",
+            dir.path(),
+        )
+        .unwrap();
+        assert_eq!(metadata21.description.as_deref(), Some("Hi"));
+        assert_eq!(
+            metadata21.description_content_type.as_deref(),
+            Some("text/x-rst")
+        );
+
+        // Neither the extension nor the table says what the content is
+        assert!(metadata_with("[project]\nreadme = \"README\"\n", dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_license_forms() {
+        let metadata21 = metadata("[project]\nlicense = \"MIT OR Apache-2.0\"\n").unwrap();
+        assert_eq!(metadata21.license.as_deref(), Some("MIT OR Apache-2.0"));
+
+        let metadata21 =
+            metadata("[project]\nlicense = { text = \"Line 1\\nLine 2\\n\" }\n").unwrap();
+        assert_eq!(
+            metadata21.license.as_deref(),
+            Some("Line 1\n        Line 2")
+        );
+
+        // The file becomes a License-File instead
+        let pyproject = "[project]\nlicense = { file = \"LICENSE\" }\n";
+        let metadata21 = metadata(pyproject).unwrap();
+        assert_eq!(metadata21.license, None);
+        let pyproject: toml::Value = toml::from_str(pyproject).unwrap();
+        assert_eq!(
+            license_file(Some(&pyproject)).unwrap().as_deref(),
+            Some("LICENSE")
+        );
+
+        assert!(metadata("[project]\nlicense = { file = \"LICENSE\", text = \"MIT\" }\n").is_err());
+    }
+
+    #[test]
+    fn test_optional_dependencies_get_extra_markers() {
+        let metadata21 = metadata(
+            r#"
+            [project]
+            dependencies = ["numpy"]
+
+            [project.optional-dependencies]
+            test = ["pytest", "pytest-cov; python_version >= '3.8'"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(metadata21.provides_extra, vec!["test"]);
+        assert_eq!(
+            metadata21.requires_dist,
+            vec![
+                "numpy",
+                "pytest; extra == \"test\"",
+                "pytest-cov; (python_version >= '3.8') and extra == \"test\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_people() {
+        let metadata21 = metadata(
+            r#"
+            [project]
+            authors = [{ name = "Jane" }, { name = "John", email = "john@example.com" }]
+            maintainers = [{ email = "team@example.com" }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(metadata21.author.as_deref(), Some("Jane"));
+        assert_eq!(
+            metadata21.author_email.as_deref(),
+            Some("John <john@example.com>")
+        );
+        assert_eq!(metadata21.maintainer, None);
+        assert_eq!(
+            metadata21.maintainer_email.as_deref(),
+            Some("team@example.com")
+        );
+    }
+}
@@ -0,0 +1,370 @@
+//! Source distributions as specified in https://packaging.python.org/specifications/source-distribution-format/
+//!
+//! maturin's source distribution asks `cargo package --list` for the files, which resolves the
+//! dependencies and so usually fails without network access in a nix build. This collects the
+//! files itself, following the rules cargo uses for `include` and `exclude`, and writes a tarball
+//! that only depends on the contents of the files and `SOURCE_DATE_EPOCH`.
+
+use crate::wheel_writer::source_date_epoch;
+use failure::{bail, Error, ResultExt};
+use flate2::{Compression, GzBuilder};
+use maturin::Metadata21;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// The pyproject.toml for crates that don't have one, so that pip knows how to build them
+const DEFAULT_PYPROJECT_TOML: &str = r#"[build-system]
+requires = ["maturin>=0.7,<0.8"]
+build-backend = "maturin"
+"#;
+
+/// A gitignore style pattern from `include` or `exclude` in Cargo.toml
+struct Pattern {
+    /// The pattern as segments between slashes, starting with `**` if it matches at any depth
+    segments: Vec<String>,
+    /// Whether the pattern only matches directories, i.e. it ends with a slash
+    dir_only: bool,
+    /// Whether the pattern starts with `!` and undoes the patterns before it
+    negated: bool,
+}
+
+impl Pattern {
+    fn new(pattern: &str) -> Pattern {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        let dir_only = pattern.ends_with('/');
+        let pattern = pattern.trim_end_matches('/');
+
+        // Like in gitignore, a pattern with a slash except at the end is relative to the root
+        let mut segments = Vec::new();
+        if !pattern.contains('/') {
+            segments.push("**".to_string());
+        }
+        segments.extend(
+            pattern
+                .trim_start_matches('/')
+                .split('/')
+                .map(ToString::to_string),
+        );
+
+        Pattern {
+            segments,
+            dir_only,
+            negated,
+        }
+    }
+
+    /// Matches the path of a file relative to the manifest directory, which also matches if one
+    /// of the directories it's in matches
+    fn matches(&self, path: &str) -> bool {
+        let components: Vec<&str> = path.split('/').collect();
+        let file_len = components.len();
+        (1..=file_len)
+            .filter(|len| !self.dir_only || *len < file_len)
+            .any(|len| match_segments(&self.segments, &components[..len]))
+    }
+}
+
+fn match_segments(segments: &[String], components: &[&str]) -> bool {
+    match segments.split_first() {
+        None => components.is_empty(),
+        // A trailing `**` matches everything inside, but not the directory itself
+        Some((first, rest)) if first == "**" && rest.is_empty() => !components.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..=components.len()).any(|skip| match_segments(rest, &components[skip..]))
+        }
+        Some((first, rest)) => match components.split_first() {
+            Some((component, components)) => {
+                match_wildcards(first.as_bytes(), component.as_bytes())
+                    && match_segments(rest, components)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Matches `*`, `?` and `[...]` within a single path component
+fn match_wildcards(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| match_wildcards(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && match_wildcards(rest, &name[1..]),
+        Some((b'[', rest)) if rest.contains(&b']') => {
+            let end = rest.iter().position(|c| *c == b']').unwrap();
+            let (negated, class) = match rest[..end].split_first() {
+                Some((b'!', class)) => (true, class),
+                _ => (false, &rest[..end]),
+            };
+            match name.split_first() {
+                Some((c, name)) => {
+                    let mut in_class = false;
+                    let mut i = 0;
+                    while i < class.len() {
+                        if i + 2 < class.len() && class[i + 1] == b'-' {
+                            in_class |= class[i] <= *c && *c <= class[i + 2];
+                            i += 3;
+                        } else {
+                            in_class |= class[i] == *c;
+                            i += 1;
+                        }
+                    }
+                    in_class != negated && match_wildcards(&rest[end + 1..], name)
+                }
+                None => false,
+            }
+        }
+        Some((c, rest)) => name.first() == Some(c) && match_wildcards(rest, &name[1..]),
+    }
+}
+
+/// Returns whether the last of the patterns that matches the path is not negated
+fn matches_any(patterns: &[Pattern], path: &str) -> bool {
+    let last_match = patterns.iter().rev().find(|pattern| pattern.matches(path));
+    matches!(last_match, Some(pattern) if !pattern.negated)
+}
+
+/// Reads a list of patterns from `[package]`
+fn patterns(manifest: &toml::Value, key: &str) -> Result<Option<Vec<Pattern>>, Error> {
+    let value = match manifest.get("package").and_then(|package| package.get(key)) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let patterns = match value.as_array() {
+        Some(patterns) => patterns,
+        None => bail!("package.{} in Cargo.toml must be a list of strings", key),
+    };
+    let mut parsed = Vec::new();
+    for pattern in patterns {
+        match pattern.as_str() {
+            Some(pattern) => parsed.push(Pattern::new(pattern)),
+            None => bail!("package.{} in Cargo.toml must be a list of strings", key),
+        }
+    }
+    Ok(Some(parsed))
+}
+
+/// Collects the files cargo would package, relative to the manifest directory.
+///
+/// With `include`, those are the files it matches, otherwise all files except the ones matched
+/// by `exclude` and hidden ones. The target directory and other packages below the manifest
+/// directory are always left out, while Cargo.toml, Cargo.lock, pyproject.toml and the readme and
+/// license file are always added. Unlike cargo, .gitignore is not consulted.
+pub fn collect_files(manifest_dir: &Path, manifest: &toml::Value) -> Result<Vec<String>, Error> {
+    let include = patterns(manifest, "include")?;
+    let exclude = patterns(manifest, "exclude")?.unwrap_or_default();
+
+    let mut always = vec![
+        "Cargo.toml".to_string(),
+        "Cargo.lock".to_string(),
+        "pyproject.toml".to_string(),
+    ];
+    for key in &["readme", "license-file"] {
+        let path = manifest
+            .get("package")
+            .and_then(|package| package.get(key))
+            .and_then(toml::Value::as_str);
+        if let Some(path) = path {
+            always.push(path.trim_start_matches("./").to_string());
+        }
+    }
+
+    let walker = WalkDir::new(manifest_dir)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .filter_entry(|entry| {
+            if entry.depth() == 0 || !entry.file_type().is_dir() {
+                return true;
+            }
+            let is_target = entry.depth() == 1 && entry.file_name() == "target";
+            let is_package = entry.path().join("Cargo.toml").is_file();
+            !is_target && !is_package
+        });
+
+    let mut files = Vec::new();
+    for entry in walker {
+        let entry = entry.context(format!("Failed to list {}", manifest_dir.display()))?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(manifest_dir).unwrap();
+        let path = relative
+            .iter()
+            .map(|component| component.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        let included = match &include {
+            _ if always.contains(&path) => true,
+            Some(include) => matches_any(include, &path),
+            None => {
+                let hidden = path.split('/').any(|component| component.starts_with('.'));
+                !hidden && !matches_any(&exclude, &path)
+            }
+        };
+        if included {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Writes `{distribution}-{version}.tar.gz` with the given files from the manifest directory,
+/// PKG-INFO and a pyproject.toml to the output directory and returns its path
+pub fn write_sdist(
+    manifest_dir: &Path,
+    files: &[String],
+    metadata21: &Metadata21,
+    output_dir: &Path,
+) -> Result<PathBuf, Error> {
+    let base_name = format!(
+        "{}-{}",
+        metadata21.get_distribution_escaped(),
+        metadata21.get_version_escaped()
+    );
+
+    // Maps the path in the tarball to the contents and whether the file is executable
+    let mut entries = BTreeMap::new();
+    for file in files {
+        let path = manifest_dir.join(file);
+        let bytes = fs::read(&path).context(format!("Can't read {}", path.display()))?;
+        entries.insert(file.clone(), (bytes, is_executable(&path)?));
+    }
+    entries
+        .entry("pyproject.toml".to_string())
+        .or_insert_with(|| (DEFAULT_PYPROJECT_TOML.as_bytes().to_vec(), false));
+    entries.insert(
+        "PKG-INFO".to_string(),
+        (metadata21.to_file_contents().into_bytes(), false),
+    );
+
+    fs::create_dir_all(output_dir)?;
+    let sdist_path = output_dir.join(format!("{}.tar.gz", base_name));
+    let file =
+        File::create(&sdist_path).context(format!("Failed to create {}", sdist_path.display()))?;
+
+    let mtime = source_date_epoch()?.max(0) as u64;
+    // The gzip header has a timestamp too, which stays at zero
+    let encoder = GzBuilder::new()
+        .mtime(0)
+        .write(file, Compression::default());
+    let mut tar = tar::Builder::new(encoder);
+    for (path, (bytes, executable)) in &entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(bytes.len() as u64);
+        header.set_mode(if *executable { 0o755 } else { 0o644 });
+        header.set_mtime(mtime);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_entry_type(tar::EntryType::Regular);
+        tar.append_data(
+            &mut header,
+            format!("{}/{}", base_name, path),
+            bytes.as_slice(),
+        )?;
+    }
+    tar.into_inner()?.finish()?;
+
+    Ok(sdist_path)
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> Result<bool, Error> {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(fs::metadata(path)?.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(_path: &Path) -> Result<bool, Error> {
+    Ok(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn included(patterns: &[&str], path: &str) -> bool {
+        let patterns: Vec<Pattern> = patterns
+            .iter()
+            .map(|pattern| Pattern::new(pattern))
+            .collect();
+        matches_any(&patterns, path)
+    }
+
+    #[test]
+    fn test_wildcard_in_a_directory() {
+        let patterns = ["examples/*"];
+        assert!(included(&patterns, "examples/demo.rs"));
+        assert!(included(&patterns, "examples/demo/main.rs"));
+        assert!(!included(&patterns, "examples"));
+        assert!(!included(&patterns, "src/examples/demo.rs"));
+    }
+
+    #[test]
+    fn test_name_without_slash_matches_at_any_depth() {
+        let patterns = ["*.pyc"];
+        assert!(included(&patterns, "a.pyc"));
+        assert!(included(&patterns, "python/pkg/a.pyc"));
+        assert!(!included(&patterns, "python/pkg/a.py"));
+    }
+
+    #[test]
+    fn test_trailing_slash_only_matches_directories() {
+        let patterns = ["build/"];
+        assert!(included(&patterns, "build/out.o"));
+        assert!(included(&patterns, "src/build/out.o"));
+        assert!(!included(&patterns, "build"));
+        assert!(!included(&patterns, "src/build"));
+    }
+
+    #[test]
+    fn test_negation_undoes_earlier_patterns() {
+        let patterns = ["src/**", "!src/secret.rs", "!tests/**"];
+        assert!(included(&patterns, "src/lib.rs"));
+        assert!(!included(&patterns, "src/secret.rs"));
+        assert!(!included(&patterns, "tests/a.rs"));
+        // A later pattern takes precedence over a negation before it
+        assert!(included(&["!*.rs", "src/*.rs"], "src/lib.rs"));
+    }
+
+    #[test]
+    fn test_character_classes() {
+        let patterns = ["data/[a-c]?.txt"];
+        assert!(included(&patterns, "data/a1.txt"));
+        assert!(included(&patterns, "data/c2.txt"));
+        assert!(!included(&patterns, "data/d1.txt"));
+        assert!(!included(&patterns, "data/a12.txt"));
+
+        let patterns = ["[!a-z_]*"];
+        assert!(included(&patterns, "README.md"));
+        assert!(!included(&patterns, "readme.md"));
+        assert!(!included(&patterns, "_private"));
+    }
+
+    #[test]
+    fn test_leading_slash_anchors_to_the_root() {
+        let patterns = ["/README.md"];
+        assert!(included(&patterns, "README.md"));
+        assert!(!included(&patterns, "docs/README.md"));
+    }
+
+    #[test]
+    fn test_double_star() {
+        let patterns = ["**/fixtures"];
+        assert!(included(&patterns, "fixtures/a.json"));
+        assert!(included(&patterns, "tests/data/fixtures/a.json"));
+
+        let patterns = ["docs/**"];
+        assert!(included(&patterns, "docs/index.md"));
+        assert!(included(&patterns, "docs/api/index.md"));
+        assert!(!included(&patterns, "docs"));
+
+        let patterns = ["a/**/b.txt"];
+        assert!(included(&patterns, "a/b.txt"));
+        assert!(included(&patterns, "a/x/y/b.txt"));
+        assert!(!included(&patterns, "c/a/b.txt"));
+    }
+}
@@ -0,0 +1,289 @@
+//! Finds and removes references to the nix store in artifacts.
+//!
+//! Artifacts built with nix tend to have the store paths of their dependencies in the RPATH and
+//! in strings. Inside nix that keeps the build closure alive through the wheel, and outside nix
+//! those paths don't exist.
+
+use failure::{Error, ResultExt};
+use goblin::elf::dynamic::{DT_RPATH, DT_RUNPATH};
+use goblin::elf::Elf;
+use std::collections::BTreeSet;
+use std::env;
+
+/// The length of the hash in store path names
+const HASH_LEN: usize = 32;
+
+/// Returns the store directory, which nix exports as NIX_STORE in builds
+pub fn store_dir() -> String {
+    env::var("NIX_STORE").unwrap_or_else(|_| "/nix/store".to_string())
+}
+
+/// Nix's base32 alphabet, which leaves out e, o, u and t
+fn is_hash_char(c: u8) -> bool {
+    c.is_ascii_digit() || (c.is_ascii_lowercase() && !b"eout".contains(&c))
+}
+
+fn is_name_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"+-._?=".contains(&c)
+}
+
+/// A reference to a store path: where its hash starts in the artifact and the path itself, i.e.
+/// `/nix/store/<hash>-<name>` without anything after the name.
+struct Reference {
+    hash_offset: usize,
+    path: String,
+}
+
+fn find_references(bytes: &[u8]) -> Vec<Reference> {
+    let prefix = format!("{}/", store_dir());
+    let prefix = prefix.as_bytes();
+
+    let mut references = Vec::new();
+    let mut start = 0;
+    while let Some(i) = find(&bytes[start..], prefix) {
+        let hash_offset = start + i + prefix.len();
+        start = hash_offset;
+
+        let hash = match bytes.get(hash_offset..hash_offset + HASH_LEN) {
+            Some(hash) if hash.iter().all(|c| is_hash_char(*c)) => hash,
+            _ => continue,
+        };
+        if bytes.get(hash_offset + HASH_LEN) != Some(&b'-') {
+            continue;
+        }
+        let name_start = hash_offset + HASH_LEN + 1;
+        let name_len = bytes[name_start..]
+            .iter()
+            .take_while(|c| is_name_char(**c))
+            .count();
+
+        let path = [
+            prefix,
+            hash,
+            b"-",
+            &bytes[name_start..name_start + name_len],
+        ]
+        .concat();
+        references.push(Reference {
+            hash_offset,
+            path: String::from_utf8_lossy(&path).to_string(),
+        });
+    }
+    references
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Decides which store paths may stay in the artifact.
+///
+/// An entry allows a store path if it's the path itself or a prefix of the name after the hash,
+/// e.g. "glibc" allows "/nix/store/<hash>-glibc-2.37-8".
+pub struct AllowList<'a>(pub &'a [String]);
+
+impl AllowList<'_> {
+    fn allows(&self, path: &str) -> bool {
+        let name = path
+            .strip_prefix(&format!("{}/", store_dir()))
+            .and_then(|path| path.split('/').next())
+            .and_then(|base_name| base_name.get(HASH_LEN + 1..))
+            .unwrap_or_default();
+        self.0
+            .iter()
+            .any(|allowed| path.starts_with(allowed.as_str()) || name.starts_with(allowed.as_str()))
+    }
+}
+
+/// Returns the store paths the artifact references that aren't allowed
+pub fn find_store_paths(bytes: &[u8], allow_list: &AllowList) -> BTreeSet<String> {
+    find_references(bytes)
+        .into_iter()
+        .map(|reference| reference.path)
+        .filter(|path| !allow_list.allows(path))
+        .collect()
+}
+
+/// What `scrub_store_paths` changed
+#[derive(Default)]
+pub struct Scrubbed {
+    /// The entries removed from the RPATH or RUNPATH of an ELF file
+    pub rpath_entries: Vec<String>,
+    /// The store paths whose hashes were replaced
+    pub zeroed: BTreeSet<String>,
+}
+
+/// Removes the store paths that aren't allowed: from the RPATH and RUNPATH of ELF files, where
+/// they would make the dynamic linker search the store, and then from everything else by
+/// replacing their hash with e's like nixpkgs' `remove-references-to`. Both keep the size of the
+/// file, so nothing else has to move.
+pub fn scrub_store_paths(bytes: &mut [u8], allow_list: &AllowList) -> Result<Scrubbed, Error> {
+    let mut scrubbed = Scrubbed::default();
+
+    if bytes.starts_with(b"\x7fELF") {
+        scrubbed.rpath_entries = scrub_rpaths(bytes, allow_list)?;
+    }
+
+    for reference in find_references(bytes) {
+        if allow_list.allows(&reference.path) {
+            continue;
+        }
+        for c in &mut bytes[reference.hash_offset..reference.hash_offset + HASH_LEN] {
+            *c = b'e';
+        }
+        scrubbed.zeroed.insert(reference.path);
+    }
+
+    Ok(scrubbed)
+}
+
+/// Rewrites the RPATH and RUNPATH strings in place without the store entries, padding the rest
+/// of the string with nul bytes
+fn scrub_rpaths(bytes: &mut [u8], allow_list: &AllowList) -> Result<Vec<String>, Error> {
+    let store_prefix = format!("{}/", store_dir());
+
+    // The offsets of the strings in the file, collected first since the elf borrows the bytes
+    let mut rpaths = Vec::new();
+    {
+        let elf = Elf::parse(bytes).context("Failed to parse the ELF file")?;
+        let dynamic = match &elf.dynamic {
+            Some(dynamic) => dynamic,
+            None => return Ok(Vec::new()),
+        };
+        // goblin already maps the address in DT_STRTAB to an offset in the file
+        let strtab_offset = dynamic.info.strtab;
+        if strtab_offset == 0 {
+            return Ok(Vec::new());
+        }
+
+        for dyn_ in &dynamic.dyns {
+            if dyn_.d_tag == DT_RPATH || dyn_.d_tag == DT_RUNPATH {
+                if let Some(Ok(rpath)) = elf.dynstrtab.get(dyn_.d_val as usize) {
+                    rpaths.push((strtab_offset + dyn_.d_val as usize, rpath.to_string()));
+                }
+            }
+        }
+    }
+
+    let mut removed = Vec::new();
+    for (offset, rpath) in rpaths {
+        let (store_entries, kept): (Vec<&str>, Vec<&str>) = rpath
+            .split(':')
+            .partition(|entry| entry.starts_with(&store_prefix) && !allow_list.allows(entry));
+        if store_entries.is_empty() {
+            continue;
+        }
+
+        let mut new_rpath = kept.join(":").into_bytes();
+        new_rpath.resize(rpath.len(), 0);
+        bytes[offset..offset + rpath.len()].copy_from_slice(&new_rpath);
+        removed.extend(store_entries.into_iter().map(ToString::to_string));
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{compile_library, store_path};
+    use std::fs;
+    use tempfile::TempDir;
+
+    /// Returns the RPATH and RUNPATH strings of the ELF file
+    fn rpaths(bytes: &[u8]) -> Vec<String> {
+        let elf = Elf::parse(bytes).unwrap();
+        let dynamic = elf.dynamic.as_ref().unwrap();
+        dynamic
+            .dyns
+            .iter()
+            .filter(|dyn_| dyn_.d_tag == DT_RPATH || dyn_.d_tag == DT_RUNPATH)
+            .map(|dyn_| {
+                let rpath = elf.dynstrtab.get(dyn_.d_val as usize).unwrap().unwrap();
+                rpath.to_string()
+            })
+            .collect()
+    }
+
+    fn scrub_library(args: &[&str], allowed: &[String]) -> (Vec<u8>, Scrubbed) {
+        let dir = TempDir::new().unwrap();
+        let library = compile_library(dir.path(), "libtest.so", "int f(void) { return 1; }", args);
+        let mut bytes = fs::read(library).unwrap();
+        let scrubbed = scrub_store_paths(&mut bytes, &AllowList(allowed)).unwrap();
+        (bytes, scrubbed)
+    }
+
+    #[test]
+    fn test_removes_store_entries_from_the_runpath() {
+        let zlib = format!("{}/lib", store_path('z', "zlib-1.3"));
+        let glibc = format!("{}/lib", store_path('g', "glibc-2.40"));
+        let rpath = format!("-Wl,-rpath,{}:/opt/lib:{}", zlib, glibc);
+        let (bytes, scrubbed) = scrub_library(&[&rpath], &["glibc".to_string()]);
+
+        assert_eq!(rpaths(&bytes), vec![format!("/opt/lib:{}", glibc)]);
+        assert_eq!(scrubbed.rpath_entries, vec![zlib]);
+        assert!(find_store_paths(&bytes, &AllowList(&["glibc".to_string()])).is_empty());
+    }
+
+    #[test]
+    fn test_removes_store_entries_from_the_rpath() {
+        let zlib = format!("{}/lib", store_path('z', "zlib-1.3"));
+        let rpath = format!("-Wl,-rpath,{}", zlib);
+        let (bytes, scrubbed) = scrub_library(&[&rpath, "-Wl,--disable-new-dtags"], &[]);
+
+        assert_eq!(rpaths(&bytes), vec![String::new()]);
+        assert_eq!(scrubbed.rpath_entries, vec![zlib]);
+    }
+
+    /// DT_STRTAB is an address, which only equals the offset in the file when the first segment
+    /// is loaded at 0
+    #[test]
+    fn test_removes_store_entries_when_loaded_at_an_offset() {
+        let zlib = format!("{}/lib", store_path('z', "zlib-1.3"));
+        let rpath = format!("-Wl,-rpath,/opt/lib:{}", zlib);
+        let (bytes, scrubbed) = scrub_library(&[&rpath, "-Wl,-Ttext-segment=0x10000000"], &[]);
+
+        assert_eq!(rpaths(&bytes), vec!["/opt/lib".to_string()]);
+        assert_eq!(scrubbed.rpath_entries, vec![zlib]);
+    }
+
+    #[test]
+    fn test_replaces_the_hashes_of_other_references() {
+        let removed = store_path('r', "python3-3.9.18");
+        let allowed = store_path('a', "glibc-2.40");
+        let source = format!(
+            "const char *removed = \"{}/bin/python3\";\nconst char *allowed = \"{}/lib\";\n",
+            removed, allowed
+        );
+
+        let dir = TempDir::new().unwrap();
+        let library = compile_library(dir.path(), "libtest.so", &source, &[]);
+        let original = fs::read(library).unwrap();
+        let mut bytes = original.clone();
+        let allow_list = ["glibc".to_string()];
+        let scrubbed = scrub_store_paths(&mut bytes, &AllowList(&allow_list)).unwrap();
+
+        let zeroed = removed.replace(&"r".repeat(32), &"e".repeat(32));
+        assert_eq!(
+            scrubbed.zeroed.into_iter().collect::<Vec<_>>(),
+            vec![removed.clone()]
+        );
+        assert_eq!(bytes.len(), original.len());
+        assert!(find(&bytes, zeroed.as_bytes()).is_some());
+        assert!(find(&bytes, removed.as_bytes()).is_none());
+        assert!(find(&bytes, allowed.as_bytes()).is_some());
+        Elf::parse(&bytes).unwrap();
+    }
+
+    #[test]
+    fn test_finds_store_paths() {
+        let path = store_path('z', "zlib-1.3");
+        let bytes = format!("\0{}/lib/libz.so\0/nix/store/short-hash\0", path).into_bytes();
+        let found: Vec<String> = find_store_paths(&bytes, &AllowList(&[]))
+            .into_iter()
+            .collect();
+        assert_eq!(found, vec![path.clone()]);
+        assert!(find_store_paths(&bytes, &AllowList(&[path])).is_empty());
+    }
+}
@@ -0,0 +1,84 @@
+//! Type stubs for the native module as specified in PEP 484 and PEP 561
+
+use failure::{bail, Error, ResultExt};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// The stub files to add to the wheel and where the `py.typed` marker goes
+pub struct Stubs {
+    /// Maps the path in the wheel to the stub file
+    pub files: Vec<(PathBuf, PathBuf)>,
+    /// The top-level package that gets a `py.typed`, unless a module without a package has the
+    /// stubs, which PEP 561 can't mark
+    pub py_typed: Option<PathBuf>,
+}
+
+/// Collects the stubs for the module, which `module_stub` is the path of in the wheel, e.g.
+/// `mypkg/_native.pyi`, or `mypkg/_native/__init__.pyi` for the cffi package.
+///
+/// A single file must be named after the module, e.g. `_native.pyi`. A directory is laid out
+/// like the wheel, with only .pyi files and maybe a `py.typed`, and must have the module's stub.
+pub fn collect_stubs(stubs_path: &Path, module_stub: &Path) -> Result<Stubs, Error> {
+    let mut files = Vec::new();
+    let mut has_py_typed = false;
+
+    if stubs_path.is_file() {
+        let module_name = module_stub
+            .with_extension("")
+            .iter()
+            .rfind(|part| *part != "__init__")
+            .map(|name| format!("{}.pyi", name.to_string_lossy()))
+            .unwrap_or_default();
+        let file_name = stubs_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if file_name != module_name {
+            bail!(
+                "The stub file {} must be named {} to match the module",
+                stubs_path.display(),
+                module_name
+            );
+        }
+        files.push((module_stub.to_path_buf(), stubs_path.to_path_buf()));
+    } else if stubs_path.is_dir() {
+        let walk = WalkDir::new(stubs_path).sort_by(|a, b| a.file_name().cmp(b.file_name()));
+        for entry in walk {
+            let entry = entry.context(format!("Failed to list {}", stubs_path.display()))?;
+            if entry.file_type().is_dir() {
+                continue;
+            }
+            let relative = entry.path().strip_prefix(stubs_path)?.to_path_buf();
+            if entry.file_name() == "py.typed" {
+                has_py_typed = true;
+            } else if !matches!(relative.extension(), Some(extension) if extension == "pyi") {
+                bail!(
+                    "{} is not a stub file, the stubs directory may only contain .pyi files",
+                    entry.path().display()
+                );
+            }
+            files.push((relative, entry.path().to_path_buf()));
+        }
+        if !files.iter().any(|(path, _)| path == module_stub) {
+            bail!(
+                "{} has no stub for the module, which must be {}",
+                stubs_path.display(),
+                stubs_path.join(module_stub).display()
+            );
+        }
+    } else {
+        bail!(
+            "{} is neither a stub file nor a directory",
+            stubs_path.display()
+        );
+    }
+
+    let py_typed = match module_stub
+        .parent()
+        .and_then(|package| package.iter().next())
+    {
+        Some(top_level) if !has_py_typed => Some(PathBuf::from(top_level)),
+        _ => None,
+    };
+    Ok(Stubs { files, py_typed })
+}
@@ -0,0 +1,45 @@
+//! Helpers for the tests that rewrite real ELF files, which are compiled with `cc`
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Compiles the C source into `<dir>/<file_name>` as a shared library, with extra arguments for
+/// the compiler like `-Wl,-rpath,...` or libraries to link
+pub fn compile_library(dir: &Path, file_name: &str, source: &str, args: &[&str]) -> PathBuf {
+    compile(
+        dir,
+        file_name,
+        source,
+        &[&["-shared", "-fPIC"], args].concat(),
+    )
+}
+
+/// Compiles the C source into the executable `<dir>/<file_name>`
+pub fn compile_executable(dir: &Path, file_name: &str, source: &str, args: &[&str]) -> PathBuf {
+    compile(dir, file_name, source, args)
+}
+
+fn compile(dir: &Path, file_name: &str, source: &str, args: &[&str]) -> PathBuf {
+    let source_path = dir.join(format!("{}.c", file_name));
+    std::fs::write(&source_path, source).unwrap();
+    let output_path = dir.join(file_name);
+    let output = Command::new("cc")
+        .arg("-o")
+        .arg(&output_path)
+        .arg(&source_path)
+        .args(args)
+        .output()
+        .expect("Failed to run cc");
+    assert!(
+        output.status.success(),
+        "cc failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output_path
+}
+
+/// A store path with a valid hash, e.g. `/nix/store/<hash>-zlib-1.2.13`
+pub fn store_path(hash_char: char, name: &str) -> String {
+    let hash = hash_char.to_string().repeat(32);
+    format!("{}/{}-{}", super::store_paths::store_dir(), hash, name)
+}
@@ -0,0 +1,317 @@
+//! The licenses of the crates that are statically linked into the artifact, collected from the
+//! sources cargo already has, so that this works in the nix sandbox without network
+
+use crate::cargo::Features;
+use crate::licenses::find_license_files;
+use failure::{bail, format_err, Error, ResultExt};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The parts of the output of `cargo metadata --format-version 1` needed for the licenses.
+///
+/// cargo_metadata's types predate `dep_kinds`, which tells build and dev-dependencies apart.
+#[derive(Deserialize)]
+struct Metadata {
+    packages: Vec<Package>,
+    resolve: Resolve,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    id: String,
+    name: String,
+    version: String,
+    /// Missing for path dependencies, which includes the crate itself
+    source: Option<String>,
+    license: Option<String>,
+    license_file: Option<String>,
+    manifest_path: PathBuf,
+    targets: Vec<Target>,
+}
+
+#[derive(Deserialize)]
+struct Target {
+    /// e.g. "lib", "proc-macro" or "bin"
+    kind: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Resolve {
+    nodes: Vec<Node>,
+}
+
+#[derive(Deserialize)]
+struct Node {
+    id: String,
+    deps: Vec<NodeDep>,
+}
+
+#[derive(Deserialize)]
+struct NodeDep {
+    pkg: String,
+    dep_kinds: Vec<DepKind>,
+}
+
+#[derive(Deserialize)]
+struct DepKind {
+    kind: Option<String>,
+}
+
+/// Returns THIRD_PARTY_LICENSES with the license and license texts of every crate from a
+/// registry or git repository the artifact statically links for the target, or the host
+/// without one, when built with the given features.
+///
+/// The dependencies come from the Cargo.lock of the crate, which must be up to date, and cargo
+/// reads their manifests from the vendor directory, registry or git checkouts it was configured
+/// with, without downloading anything. Build and dev-dependencies and proc macros only run on
+/// the build machine, so they and their dependencies are left out.
+pub fn third_party_licenses(
+    manifest_path: &Path,
+    target: Option<&str>,
+    features: &Features,
+) -> Result<String, Error> {
+    let target = match target {
+        Some(target) => target.to_string(),
+        None => host_triple()?,
+    };
+    let metadata = cargo_metadata(manifest_path, &target, features)?;
+
+    let manifest_path = manifest_path
+        .canonicalize()
+        .context(format!("Can't resolve {}", manifest_path.display()))?;
+    let root = metadata
+        .packages
+        .iter()
+        .find(|package| package.manifest_path == manifest_path)
+        .ok_or_else(|| format_err!("cargo metadata doesn't list {}", manifest_path.display()))?;
+
+    let proc_macros: BTreeSet<&str> = metadata
+        .packages
+        .iter()
+        .filter(|package| {
+            package
+                .targets
+                .iter()
+                .any(|target| target.kind.iter().any(|kind| kind == "proc-macro"))
+        })
+        .map(|package| package.id.as_str())
+        .collect();
+    let nodes: BTreeMap<&str, &Node> = metadata
+        .resolve
+        .nodes
+        .iter()
+        .map(|node| (node.id.as_str(), node))
+        .collect();
+    let mut dependencies = BTreeSet::new();
+    let mut queue = vec![root.id.as_str()];
+    while let Some(id) = queue.pop() {
+        let deps = nodes
+            .get(id)
+            .map(|node| node.deps.as_slice())
+            .unwrap_or(&[]);
+        for dep in deps {
+            // Normal dependencies have no kind
+            let is_normal = dep.dep_kinds.iter().any(|dep_kind| dep_kind.kind.is_none());
+            if is_normal
+                && !proc_macros.contains(dep.pkg.as_str())
+                && dependencies.insert(dep.pkg.as_str())
+            {
+                queue.push(&dep.pkg);
+            }
+        }
+    }
+
+    let mut packages: Vec<&Package> = metadata
+        .packages
+        .iter()
+        .filter(|package| dependencies.contains(package.id.as_str()) && package.source.is_some())
+        .collect();
+    packages.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+
+    let mut text = String::new();
+    for package in packages {
+        text += &package_licenses(package)?;
+    }
+    Ok(text)
+}
+
+/// Runs `cargo metadata` for the dependencies on the target with the features, which must all
+/// be available offline, as they are when the crate was built before
+fn cargo_metadata(
+    manifest_path: &Path,
+    target: &str,
+    features: &Features,
+) -> Result<Metadata, Error> {
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let mut command = Command::new(cargo);
+    command
+        .arg("metadata")
+        .arg("--manifest-path")
+        .arg(manifest_path)
+        .args(["--format-version", "1", "--locked", "--offline"])
+        .args(["--filter-platform", target]);
+    features.add_args(&mut command);
+    let output = command.output().context("Failed to run cargo metadata")?;
+    if !output.status.success() {
+        bail!(
+            "cargo metadata failed with {}. Is Cargo.lock up to date and were the \
+             dependencies fetched or vendored?\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+    let metadata =
+        serde_json::from_slice(&output.stdout).context("Failed to parse cargo's metadata")?;
+    Ok(metadata)
+}
+
+/// Asks rustc for the triple of the platform it runs on
+fn host_triple() -> Result<String, Error> {
+    let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    let output = Command::new(rustc)
+        .arg("-vV")
+        .output()
+        .context("Failed to run rustc -vV")?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match stdout.lines().find_map(|line| line.strip_prefix("host: ")) {
+        Some(host) => Ok(host.trim().to_string()),
+        None => bail!("rustc -vV didn't print the host triple"),
+    }
+}
+
+/// Formats the entry of a crate: its name, version, source and license followed by the texts of
+/// its license files
+fn package_licenses(package: &Package) -> Result<String, Error> {
+    let license = package.license.as_deref().unwrap_or("UNKNOWN");
+    let package_dir = package.manifest_path.parent().unwrap();
+    let license_files =
+        find_license_files(package_dir, package.license_file.as_deref()).context(format!(
+            "Invalid license files for {} {}",
+            package.name, package.version
+        ))?;
+    if license_files.is_empty() {
+        eprintln!(
+            "⚠️  {} {} has no license file, only the license {}",
+            package.name, package.version, license
+        );
+    }
+
+    // The source has the exact revision of git dependencies after a '#'
+    let source = package.source.as_deref().unwrap_or_default();
+    let mut text = format!(
+        "{}\n{} {}\nSource: {}\nLicense: {}\n",
+        "=".repeat(80),
+        package.name,
+        package.version,
+        source,
+        license
+    );
+    for (name, path) in license_files {
+        let license_text =
+            fs::read_to_string(&path).context(format!("Can't read {}", path.display()))?;
+        text += &format!("\n--- {} ---\n\n{}\n", name, license_text.trim_end());
+    }
+    text += "\n";
+    Ok(text)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Writes a crate with an optional path dependency on another one and locks it
+    fn crate_with_optional_dependency(dir: &Path) -> PathBuf {
+        fs::create_dir_all(dir.join("hello/src")).unwrap();
+        fs::create_dir_all(dir.join("foo/src")).unwrap();
+        fs::write(
+            dir.join("hello/Cargo.toml"),
+            r#"
+            [package]
+            name = "hello"
+            version = "0.1.0"
+
+            [dependencies]
+            foo = { path = "../foo", optional = true }
+            "#,
+        )
+        .unwrap();
+        fs::write(dir.join("hello/src/lib.rs"), "").unwrap();
+        fs::write(
+            dir.join("foo/Cargo.toml"),
+            "[package]\nname = \"foo\"\nversion = \"0.1.0\"\n",
+        )
+        .unwrap();
+        fs::write(dir.join("foo/src/lib.rs"), "").unwrap();
+
+        let manifest_path = dir.join("hello/Cargo.toml");
+        let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+        let status = Command::new(cargo)
+            .arg("generate-lockfile")
+            .arg("--offline")
+            .arg("--manifest-path")
+            .arg(&manifest_path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        manifest_path
+    }
+
+    /// Returns the names of the packages the crate depends on with the features
+    fn dependency_names(manifest_path: &Path, features: &Features) -> Vec<String> {
+        let metadata = cargo_metadata(manifest_path, &host_triple().unwrap(), features).unwrap();
+        let root = metadata
+            .packages
+            .iter()
+            .find(|package| package.name == "hello")
+            .unwrap();
+        let node = metadata
+            .resolve
+            .nodes
+            .iter()
+            .find(|node| node.id == root.id)
+            .unwrap();
+        node.deps
+            .iter()
+            .map(|dep| {
+                let package = metadata
+                    .packages
+                    .iter()
+                    .find(|package| package.id == dep.pkg);
+                package.unwrap().name.clone()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_optional_dependency_needs_its_feature() {
+        let dir = TempDir::new().unwrap();
+        let manifest_path = crate_with_optional_dependency(dir.path());
+
+        let no_features = Features {
+            features: &[],
+            all_features: false,
+            no_default_features: false,
+        };
+        assert!(dependency_names(&manifest_path, &no_features).is_empty());
+
+        let foo = ["foo".to_string()];
+        let with_foo = Features {
+            features: &foo,
+            all_features: false,
+            no_default_features: false,
+        };
+        assert_eq!(dependency_names(&manifest_path, &with_foo), vec!["foo"]);
+
+        let all_features = Features {
+            features: &[],
+            all_features: true,
+            no_default_features: false,
+        };
+        assert_eq!(dependency_names(&manifest_path, &all_features), vec!["foo"]);
+    }
+}
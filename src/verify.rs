@@ -0,0 +1,597 @@
+//! Checks an existing wheel for the mistakes that make pip reject it or the module fail to import
+
+use crate::artifact;
+use failure::{format_err, Error, ResultExt};
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use zip::ZipArchive;
+
+/// The metadata versions up to the 2.4 of PEP 639
+const METADATA_VERSIONS: &[&str] = &["1.0", "1.1", "1.2", "2.1", "2.2", "2.3", "2.4"];
+
+/// Returns every problem found in the wheel, which is valid if there are none.
+///
+/// Only a wheel that can't be opened at all is an error.
+pub fn verify_wheel(wheel_path: &Path) -> Result<Vec<String>, Error> {
+    let file = File::open(wheel_path).context(format!("Can't read {}", wheel_path.display()))?;
+    let mut archive =
+        ZipArchive::new(file).context(format!("{} is not a zip archive", wheel_path.display()))?;
+
+    let mut files = BTreeMap::new();
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        if entry.name().ends_with('/') {
+            continue;
+        }
+        let mut bytes = Vec::new();
+        entry
+            .read_to_end(&mut bytes)
+            .context(format!("Can't read {} from the wheel", entry.name()))?;
+        files.insert(entry.name().to_string(), bytes);
+    }
+
+    let mut problems = Vec::new();
+
+    let filename = wheel_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let name_parts: Vec<&str> = filename.trim_end_matches(".whl").split('-').collect();
+    if !filename.ends_with(".whl") || !(name_parts.len() == 5 || name_parts.len() == 6) {
+        problems.push(format!(
+            "the file name {} is not of the form \
+             {{distribution}}-{{version}}(-{{build}})?-{{python}}-{{abi}}-{{platform}}.whl",
+            filename
+        ));
+        return Ok(problems);
+    }
+    let (distribution, version) = (name_parts[0], name_parts[1]);
+    let tag_parts = &name_parts[name_parts.len() - 3..];
+
+    let dist_info = format!("{}-{}.dist-info", distribution, version);
+    let mut dist_info_file = |name: &str| {
+        let path = format!("{}/{}", dist_info, name);
+        let contents = files.get(&path).map(|bytes| String::from_utf8_lossy(bytes));
+        if contents.is_none() {
+            problems.push(format!("{} is missing", path));
+        }
+        contents.map(|contents| (path, contents.to_string()))
+    };
+    let wheel_file = dist_info_file("WHEEL");
+    let metadata_file = dist_info_file("METADATA");
+    let record_file = dist_info_file("RECORD");
+
+    if let Some((path, contents)) = wheel_file {
+        check_wheel_file(&path, &contents, tag_parts, &mut problems);
+    }
+    if let Some((path, contents)) = metadata_file {
+        check_metadata(
+            &path,
+            &contents,
+            distribution,
+            version,
+            &files,
+            &mut problems,
+        );
+    }
+    if let Some((path, contents)) = record_file {
+        check_record(&path, &contents, &files, &mut problems);
+    }
+    check_native_modules(&files, &mut problems);
+
+    Ok(problems)
+}
+
+/// Parses the email header format of METADATA and WHEEL into its fields, ignoring the body.
+///
+/// Fields can occur more than once and continuation lines are joined to the field before them.
+fn parse_headers(contents: &str) -> Result<Vec<(String, String)>, String> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for line in contents.lines() {
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with(' ') || line.starts_with('\t') {
+            match fields.last_mut() {
+                Some((_, value)) => *value += &format!("\n{}", line.trim_start()),
+                None => {
+                    return Err(format!(
+                        "continuation line before the first field: {}",
+                        line
+                    ))
+                }
+            }
+            continue;
+        }
+        match line.find(':') {
+            Some(i) => fields.push((line[..i].to_string(), line[i + 1..].trim().to_string())),
+            None => {
+                return Err(format!(
+                    "expected a field of the form Key: value, got {}",
+                    line
+                ))
+            }
+        }
+    }
+    Ok(fields)
+}
+
+fn get_field<'a>(fields: &'a [(String, String)], key: &str) -> Vec<&'a str> {
+    fields
+        .iter()
+        .filter(|(field, _)| field.eq_ignore_ascii_case(key))
+        .map(|(_, value)| value.as_str())
+        .collect()
+}
+
+/// Checks that the WHEEL file is valid and lists the same tags as the file name, whose
+/// compressed tag sets like "py2.py3" stand for every combination.
+fn check_wheel_file(path: &str, contents: &str, tag_parts: &[&str], problems: &mut Vec<String>) {
+    let fields = match parse_headers(contents) {
+        Ok(fields) => fields,
+        Err(err) => return problems.push(format!("{} can't be parsed: {}", path, err)),
+    };
+    for key in &["Wheel-Version", "Root-Is-Purelib"] {
+        if get_field(&fields, key).is_empty() {
+            problems.push(format!("{} has no {}", path, key));
+        }
+    }
+
+    let mut filename_tags = BTreeSet::new();
+    for python in tag_parts[0].split('.') {
+        for abi in tag_parts[1].split('.') {
+            for platform in tag_parts[2].split('.') {
+                filename_tags.insert(format!("{}-{}-{}", python, abi, platform));
+            }
+        }
+    }
+    let wheel_tags: BTreeSet<String> = get_field(&fields, "Tag")
+        .into_iter()
+        .map(ToString::to_string)
+        .collect();
+    for tag in filename_tags.difference(&wheel_tags) {
+        problems.push(format!(
+            "the file name has tag {}, but {} doesn't",
+            tag, path
+        ));
+    }
+    for tag in wheel_tags.difference(&filename_tags) {
+        problems.push(format!(
+            "{} has tag {}, but the file name doesn't",
+            path, tag
+        ));
+    }
+}
+
+/// Normalizes a distribution name for comparison, as in the escaping for file names
+fn normalize_name(name: &str) -> String {
+    let mut normalized = String::new();
+    for c in name.chars() {
+        let c = if c == '-' || c == '.' { '_' } else { c };
+        if !(c == '_' && normalized.ends_with('_')) {
+            normalized.push(c.to_ascii_lowercase());
+        }
+    }
+    normalized
+}
+
+/// Checks that METADATA is core metadata for the distribution and version in the file name,
+/// whose license files are in the wheel
+fn check_metadata(
+    path: &str,
+    contents: &str,
+    distribution: &str,
+    version: &str,
+    files: &BTreeMap<String, Vec<u8>>,
+    problems: &mut Vec<String>,
+) {
+    let fields = match parse_headers(contents) {
+        Ok(fields) => fields,
+        Err(err) => return problems.push(format!("{} can't be parsed: {}", path, err)),
+    };
+
+    match get_field(&fields, "Metadata-Version").as_slice() {
+        [] => problems.push(format!("{} has no Metadata-Version", path)),
+        [metadata_version] if !METADATA_VERSIONS.contains(metadata_version) => problems.push(
+            format!("{} has unknown Metadata-Version {}", path, metadata_version),
+        ),
+        [_] => {}
+        _ => problems.push(format!("{} has more than one Metadata-Version", path)),
+    }
+
+    match get_field(&fields, "Name").as_slice() {
+        [] => problems.push(format!("{} has no Name", path)),
+        [name] if normalize_name(name) != normalize_name(distribution) => problems.push(format!(
+            "{} has Name {}, but the file name has {}",
+            path, name, distribution
+        )),
+        _ => {}
+    }
+
+    match get_field(&fields, "Version").as_slice() {
+        [] => problems.push(format!("{} has no Version", path)),
+        [metadata_version] if escape_version(metadata_version) != version => {
+            problems.push(format!(
+                "{} has Version {}, but the file name has {}",
+                path, metadata_version, version
+            ))
+        }
+        _ => {}
+    }
+
+    let licenses_dir = path.replace("METADATA", "licenses");
+    for license_file in get_field(&fields, "License-File") {
+        let license_path = format!("{}/{}", licenses_dir, license_file);
+        if !files.contains_key(&license_path) {
+            problems.push(format!(
+                "{} has License-File {}, but {} is missing",
+                path, license_file, license_path
+            ));
+        }
+    }
+}
+
+/// Escapes a version for file names, where runs of characters other than alphanumerics and dots,
+/// like the "+" of a local version, become an underscore
+fn escape_version(version: &str) -> String {
+    let mut escaped = String::new();
+    let mut in_run = false;
+    for c in version.chars() {
+        if c.is_alphanumeric() || c == '.' || c == '_' {
+            escaped.push(c);
+            in_run = false;
+        } else if !in_run {
+            escaped.push('_');
+            in_run = true;
+        }
+    }
+    escaped
+}
+
+/// Splits a line of RECORD, which is csv, into its fields
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+/// Checks that RECORD lists every file in the wheel with the right hash and size
+fn check_record(
+    path: &str,
+    contents: &str,
+    files: &BTreeMap<String, Vec<u8>>,
+    problems: &mut Vec<String>,
+) {
+    let mut recorded = BTreeSet::new();
+    for line in contents.lines().filter(|line| !line.is_empty()) {
+        let fields = split_csv_line(line);
+        let (file, hash, size) = match fields.as_slice() {
+            [file, hash, size] => (file, hash, size),
+            _ => {
+                problems.push(format!("{} has a malformed line: {}", path, line));
+                continue;
+            }
+        };
+        recorded.insert(file.clone());
+
+        let bytes = match files.get(file) {
+            Some(bytes) => bytes,
+            None => {
+                problems.push(format!("{} lists {}, which isn't in the wheel", path, file));
+                continue;
+            }
+        };
+        // RECORD can't contain its own hash
+        if file == path {
+            continue;
+        }
+
+        match check_hash(hash, bytes) {
+            Ok(true) => {}
+            Ok(false) => problems.push(format!(
+                "the hash of {} doesn't match the one in {}",
+                file, path
+            )),
+            Err(err) => problems.push(format!("{} has {} for {}", path, err, file)),
+        }
+        if size.parse::<usize>().ok() != Some(bytes.len()) {
+            problems.push(format!(
+                "{} is {} bytes, but {} says {}",
+                file,
+                bytes.len(),
+                path,
+                size
+            ));
+        }
+    }
+
+    let signatures = [format!("{}.jws", path), format!("{}.p7s", path)];
+    for file in files.keys() {
+        if !recorded.contains(file) && !signatures.contains(file) {
+            problems.push(format!("{} is missing from {}", file, path));
+        }
+    }
+}
+
+/// Compares the `algorithm=urlsafe-base64-digest` hash of RECORD with the file's contents
+fn check_hash(hash: &str, bytes: &[u8]) -> Result<bool, Error> {
+    let mut parts = hash.splitn(2, '=');
+    let algorithm = parts.next().unwrap_or_default();
+    let expected = parts
+        .next()
+        .ok_or_else(|| format_err!("no hash of the form algorithm=digest"))?;
+    let digest = match algorithm {
+        "sha256" => Sha256::digest(bytes).to_vec(),
+        "sha384" => Sha384::digest(bytes).to_vec(),
+        "sha512" => Sha512::digest(bytes).to_vec(),
+        _ => return Err(format_err!("unsupported hash algorithm {}", algorithm)),
+    };
+    Ok(base64::encode_config(&digest, base64::URL_SAFE_NO_PAD) == expected)
+}
+
+/// Whether the file is named like the native modules the bridges write, e.g.
+/// `hello.cpython-39-x86_64-linux-gnu.so`, `hello.abi3.so` or `hello.pyd`. Helper libraries like
+/// the `libfoo.so` bundled by --bundle-libs or cffi's `native.so`, which is loaded by its `ffi.py`,
+/// aren't imported by python and have no `PyInit_` function.
+fn is_extension_module(file_name: &str) -> bool {
+    if file_name.ends_with(".pyd") {
+        return true;
+    }
+    match file_name.split('.').collect::<Vec<_>>().as_slice() {
+        [_, tag, "so"] => *tag == "abi3" || tag.starts_with("cpython-") || tag.starts_with("pypy"),
+        _ => false,
+    }
+}
+
+/// Checks that each native module exports the `PyInit_` function python looks for, which is
+/// named after the file up to the first dot.
+fn check_native_modules(files: &BTreeMap<String, Vec<u8>>, problems: &mut Vec<String>) {
+    for (file, bytes) in files {
+        // Scripts, headers and data files aren't imported
+        let importable = !file.contains(".data/")
+            || file.contains(".data/purelib/")
+            || file.contains(".data/platlib/");
+        if !importable {
+            continue;
+        }
+        let file_name = file.rsplit('/').next().unwrap();
+        if !is_extension_module(file_name) {
+            continue;
+        }
+        let module_name = file_name.split('.').next().unwrap();
+
+        let init = format!("PyInit_{}", module_name);
+        let symbols = match artifact::exported_symbols(bytes) {
+            Ok(symbols) => symbols,
+            Err(err) => {
+                problems.push(format!("{} is not a valid shared library: {}", file, err));
+                continue;
+            }
+        };
+        if !symbols.contains(&init) {
+            let inits: Vec<&str> = symbols
+                .iter()
+                .map(String::as_str)
+                .filter(|symbol| symbol.starts_with("PyInit_"))
+                .collect();
+            problems.push(match inits.as_slice() {
+                [] => format!("{} doesn't export {} or any other PyInit_", file, init),
+                _ => format!(
+                    "{} doesn't export {}, but {}; is the module name right?",
+                    file,
+                    init,
+                    inits.join(", ")
+                ),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::compile_library;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn record_line(file: &str, bytes: &[u8]) -> String {
+        let hash = base64::encode_config(&Sha256::digest(bytes), base64::URL_SAFE_NO_PAD);
+        format!("{},sha256={},{}\n", file, hash, bytes.len())
+    }
+
+    #[test]
+    fn test_tags_must_match_the_file_name() {
+        let mut problems = Vec::new();
+        let wheel = "Wheel-Version: 1.0\nRoot-Is-Purelib: false\n\
+                     Tag: py2-none-any\nTag: py3-none-any\n";
+        check_wheel_file("WHEEL", wheel, &["py2.py3", "none", "any"], &mut problems);
+        assert!(problems.is_empty(), "{:?}", problems);
+
+        check_wheel_file(
+            "WHEEL",
+            wheel,
+            &["py3", "none", "linux_x86_64"],
+            &mut problems,
+        );
+        assert_eq!(
+            problems,
+            vec![
+                "the file name has tag py3-none-linux_x86_64, but WHEEL doesn't",
+                "WHEEL has tag py2-none-any, but the file name doesn't",
+                "WHEEL has tag py3-none-any, but the file name doesn't",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_metadata_must_match_the_file_name() {
+        let mut problems = Vec::new();
+        let metadata = "Metadata-Version: 2.1\nName: hello-py\nVersion: 1.0.0+nix\n";
+        check_metadata(
+            "METADATA",
+            metadata,
+            "hello_py",
+            "1.0.0_nix",
+            &BTreeMap::new(),
+            &mut problems,
+        );
+        assert!(problems.is_empty(), "{:?}", problems);
+
+        check_metadata(
+            "METADATA",
+            metadata,
+            "world",
+            "1.0.0",
+            &BTreeMap::new(),
+            &mut problems,
+        );
+        assert_eq!(
+            problems,
+            vec![
+                "METADATA has Name hello-py, but the file name has world",
+                "METADATA has Version 1.0.0+nix, but the file name has 1.0.0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_license_file_must_be_in_the_wheel() {
+        let path = "hello-1.0.dist-info/METADATA";
+        let metadata = "Metadata-Version: 2.4\nName: hello\nVersion: 1.0\n\
+                        License-File: LICENSE-MIT\nLicense-File: LICENSE-APACHE\n";
+        let mut files = BTreeMap::new();
+        files.insert(
+            "hello-1.0.dist-info/licenses/LICENSE-MIT".to_string(),
+            Vec::new(),
+        );
+        let mut problems = Vec::new();
+        check_metadata(path, metadata, "hello", "1.0", &files, &mut problems);
+        assert_eq!(
+            problems,
+            vec![
+                "hello-1.0.dist-info/METADATA has License-File LICENSE-APACHE, \
+                 but hello-1.0.dist-info/licenses/LICENSE-APACHE is missing"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_record_hashes_and_sizes() {
+        let mut files = BTreeMap::new();
+        files.insert("hello/__init__.py".to_string(), b"import os\n".to_vec());
+        files.insert("hello/world.py".to_string(), b"print(1)\n".to_vec());
+        files.insert("hello-1.0.dist-info/RECORD".to_string(), Vec::new());
+
+        let mut record = record_line("hello/__init__.py", b"import os\n");
+        record += &record_line("hello/world.py", b"print(1)\n");
+        record += "hello-1.0.dist-info/RECORD,,\n";
+        let mut problems = Vec::new();
+        check_record("hello-1.0.dist-info/RECORD", &record, &files, &mut problems);
+        assert!(problems.is_empty(), "{:?}", problems);
+
+        let mut record = record_line("hello/__init__.py", b"import sys\n");
+        record += &record_line("hello/world.py", b"print(1)\n").replace(",9\n", ",10\n");
+        record += "hello/gone.py,sha256=abc,3\n";
+        record += "hello-1.0.dist-info/RECORD,,\n";
+        check_record("hello-1.0.dist-info/RECORD", &record, &files, &mut problems);
+        assert_eq!(
+            problems,
+            vec![
+                "the hash of hello/__init__.py doesn't match the one in hello-1.0.dist-info/RECORD",
+                "hello/__init__.py is 10 bytes, but hello-1.0.dist-info/RECORD says 11",
+                "hello/world.py is 9 bytes, but hello-1.0.dist-info/RECORD says 10",
+                "hello-1.0.dist-info/RECORD lists hello/gone.py, which isn't in the wheel",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_files_missing_from_record() {
+        let mut files = BTreeMap::new();
+        files.insert("hello/__init__.py".to_string(), Vec::new());
+        files.insert("hello-1.0.dist-info/RECORD".to_string(), Vec::new());
+        files.insert("hello-1.0.dist-info/RECORD.jws".to_string(), Vec::new());
+        let mut problems = Vec::new();
+        check_record(
+            "hello-1.0.dist-info/RECORD",
+            "hello-1.0.dist-info/RECORD,,\n",
+            &files,
+            &mut problems,
+        );
+        assert_eq!(
+            problems,
+            vec!["hello/__init__.py is missing from hello-1.0.dist-info/RECORD"]
+        );
+    }
+
+    #[test]
+    fn test_native_module_must_export_pyinit() {
+        let dir = TempDir::new().unwrap();
+        let library = compile_library(dir.path(), "hello.so", "void PyInit_hello() {}", &[]);
+        let bytes = fs::read(library).unwrap();
+        let mut files = BTreeMap::new();
+        files.insert(
+            "hello/hello.cpython-39-x86_64-linux-gnu.so".to_string(),
+            bytes.clone(),
+        );
+        files.insert("hello/other.abi3.so".to_string(), bytes);
+        let mut problems = Vec::new();
+        check_native_modules(&files, &mut problems);
+        assert_eq!(
+            problems,
+            vec![
+                "hello/other.abi3.so doesn't export PyInit_other, but PyInit_hello; \
+                 is the module name right?"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_helper_libraries_are_not_native_modules() {
+        let dir = TempDir::new().unwrap();
+        let library = compile_library(dir.path(), "libfoo.so", "int foo() { return 1; }", &[]);
+        let bytes = fs::read(library).unwrap();
+        let mut files = BTreeMap::new();
+        // As bundled by --bundle-libs
+        files.insert("hello/.libs/libfoo.so".to_string(), bytes.clone());
+        files.insert("hello.libs/libfoo-0123abcd.so.1".to_string(), bytes.clone());
+        files.insert("hello/lib/libfoo.so".to_string(), bytes.clone());
+        files.insert("hello/native.so".to_string(), bytes.clone());
+        files.insert("hello/ffi.py".to_string(), Vec::new());
+        files.insert(
+            "hello-1.0.data/scripts/hello.cpython-39-x86_64-linux-gnu.so".to_string(),
+            bytes,
+        );
+        let mut problems = Vec::new();
+        check_native_modules(&files, &mut problems);
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    #[test]
+    fn test_extension_module_names() {
+        assert!(is_extension_module("hello.cpython-39-x86_64-linux-gnu.so"));
+        assert!(is_extension_module("hello.cpython-36m-darwin.so"));
+        assert!(is_extension_module("hello.pypy37-pp73-x86_64-linux-gnu.so"));
+        assert!(is_extension_module("hello.abi3.so"));
+        assert!(is_extension_module("hello.cp39-win_amd64.pyd"));
+        assert!(is_extension_module("hello.pyd"));
+        assert!(!is_extension_module("libfoo.so"));
+        assert!(!is_extension_module("libfoo.so.1"));
+        assert!(!is_extension_module("native.so"));
+    }
+}
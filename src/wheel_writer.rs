@@ -0,0 +1,308 @@
+//! A wheel writer that produces bit-for-bit reproducible archives, which nix requires.
+//!
+//! maturin's WheelWriter stamps every entry with the current time and writes entries in the order
+//! they were added. This one keeps the entries in memory and only writes the zip on finish:
+//! sorted, with the .dist-info directory at the end as PEP 427 recommends, with a fixed mtime
+//! from `SOURCE_DATE_EPOCH` and with permissions normalized to 644 or 755.
+
+use failure::{bail, Error, ResultExt};
+use maturin::{write_dist_info, Metadata21, ModuleWriter};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use zip::{DateTime, ZipWriter};
+
+pub struct WheelWriter {
+    /// Maps the path inside the wheel to the contents and whether it's executable
+    entries: BTreeMap<String, (Vec<u8>, bool)>,
+    dist_info_dir: String,
+    wheel_path: PathBuf,
+}
+
+impl ModuleWriter for WheelWriter {
+    fn add_directory(&mut self, _path: impl AsRef<Path>) -> Result<(), Error> {
+        Ok(()) // Directories are implied by the paths in zip archives
+    }
+
+    fn add_bytes_with_permissions(
+        &mut self,
+        target: impl AsRef<Path>,
+        bytes: &[u8],
+        permissions: u32,
+    ) -> Result<(), Error> {
+        // Wheels always use forward slashes
+        let target = target.as_ref().to_string_lossy().replace('\\', "/");
+        if self.entries.contains_key(&target) {
+            bail!("{} was added to the wheel twice", target);
+        }
+        let executable = permissions & 0o111 != 0;
+        self.entries.insert(target, (bytes.to_vec(), executable));
+        Ok(())
+    }
+}
+
+impl WheelWriter {
+    /// Starts a wheel in the given directory and adds the METADATA and WHEEL files to it, as
+    /// well as the license files, which map their path in `.dist-info/licenses` to the file
+    pub fn new(
+        tag: &str,
+        wheel_dir: &Path,
+        metadata21: &Metadata21,
+        tags: &[String],
+        license_files: &[(String, PathBuf)],
+    ) -> Result<WheelWriter, Error> {
+        fs::create_dir_all(wheel_dir)?;
+        let wheel_path = wheel_dir.join(format!(
+            "{}-{}-{}.whl",
+            metadata21.get_distribution_escaped(),
+            metadata21.get_version_escaped(),
+            tag
+        ));
+
+        let mut writer = WheelWriter {
+            entries: BTreeMap::new(),
+            dist_info_dir: metadata21.get_dist_info_dir().to_string_lossy().to_string(),
+            wheel_path,
+        };
+        // Entry points are written separately since maturin only supports console scripts
+        write_dist_info(&mut writer, metadata21, &HashMap::new(), tags)?;
+
+        if !license_files.is_empty() {
+            // maturin can't write License-File headers, so its METADATA is replaced
+            let names: Vec<&str> = license_files
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect();
+            let metadata = metadata_file(metadata21, &names);
+            let metadata_path = format!("{}/METADATA", writer.dist_info_dir);
+            writer
+                .entries
+                .insert(metadata_path, (metadata.into_bytes(), false));
+
+            let licenses_dir = metadata21.get_dist_info_dir().join("licenses");
+            for (name, path) in license_files {
+                writer
+                    .add_file(licenses_dir.join(name), path)
+                    .context(format!("Failed to add {}", path.display()))?;
+            }
+        }
+
+        Ok(writer)
+    }
+
+    /// Writes the zip with the RECORD file and returns its path
+    pub fn finish(self) -> Result<PathBuf, Error> {
+        let mtime = zip_date_time()?;
+        let file = File::create(&self.wheel_path)
+            .context(format!("Failed to create {}", self.wheel_path.display()))?;
+        let mut zip = ZipWriter::new(file);
+
+        let in_dist_info = |path: &str| path.starts_with(&format!("{}/", self.dist_info_dir));
+        let (dist_info, package): (Vec<_>, Vec<_>) = self
+            .entries
+            .iter()
+            .partition(|(path, _)| in_dist_info(path));
+
+        let mut record = String::new();
+        for (path, (bytes, executable)) in package.into_iter().chain(dist_info) {
+            let permissions = if *executable { 0o755 } else { 0o644 };
+            let options = zip::write::FileOptions::default()
+                .compression_method(zip::CompressionMethod::Deflated)
+                .last_modified_time(mtime)
+                .unix_permissions(permissions);
+            zip.start_file(path.as_str(), options)?;
+            zip.write_all(bytes)?;
+
+            let hash = base64::encode_config(&Sha256::digest(bytes), base64::URL_SAFE_NO_PAD);
+            record += &format!("{},sha256={},{}\n", path, hash, bytes.len());
+        }
+
+        let record_path = format!("{}/RECORD", self.dist_info_dir);
+        record += &format!("{},,\n", record_path);
+        let options = zip::write::FileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .last_modified_time(mtime)
+            .unix_permissions(0o644);
+        zip.start_file(record_path, options)?;
+        zip.write_all(record.as_bytes())?;
+
+        zip.finish()?;
+        Ok(self.wheel_path)
+    }
+}
+
+/// Formats METADATA like maturin does, with a `License-File` header for each license file, which
+/// needs metadata version 2.4
+fn metadata_file(metadata21: &Metadata21, license_files: &[&str]) -> String {
+    let mut headers = metadata21.clone();
+    headers.metadata_version = "2.4".to_string();
+    headers.description = None;
+
+    let mut contents = headers.to_file_contents();
+    for license_file in license_files {
+        contents += &format!("License-File: {}\n", license_file);
+    }
+    if let Some(description) = &metadata21.description {
+        contents += &format!("\n{}\n", description);
+    }
+    contents
+}
+
+/// Returns `SOURCE_DATE_EPOCH` or, if it isn't set, 1980-01-01, the earliest date zip supports
+pub fn source_date_epoch() -> Result<i64, Error> {
+    match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => Ok(epoch.trim().parse::<i64>().context(format!(
+            "SOURCE_DATE_EPOCH must be an integer, got {}",
+            epoch
+        ))?),
+        Err(_) => Ok(315_532_800),
+    }
+}
+
+/// Returns the timestamp for all entries, which is the source date epoch clamped to the range
+/// zip can represent
+fn zip_date_time() -> Result<DateTime, Error> {
+    // 1980-01-01 and 2107-12-31 23:59:58, the limits of the dos timestamps used by zip
+    let epoch = source_date_epoch()?.clamp(315_532_800, 4_354_819_198);
+
+    let days = epoch.div_euclid(86_400);
+    let seconds = epoch.rem_euclid(86_400);
+    let (year, month, day) = civil_from_days(days);
+    DateTime::from_date_and_time(
+        year as u16,
+        month as u8,
+        day as u8,
+        (seconds / 3600) as u8,
+        (seconds % 3600 / 60) as u8,
+        (seconds % 60) as u8,
+    )
+    .map_err(|()| failure::format_err!("SOURCE_DATE_EPOCH {} is out of range", epoch))
+}
+
+/// Converts days since 1970-01-01 into a (year, month, day) date in the proleptic gregorian
+/// calendar, following http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use maturin::CargoToml;
+    use tempfile::TempDir;
+    use zip::ZipArchive;
+
+    fn metadata21() -> Metadata21 {
+        let cargo_toml: CargoToml =
+            toml::from_str("[package]\nname = \"hello\"\nversion = \"0.1.0\"\nauthors = []\n")
+                .unwrap();
+        Metadata21::from_cargo_toml(&cargo_toml, ".").unwrap()
+    }
+
+    /// Writes a wheel with a module, a script and a native module, adding them in the given order
+    fn write_wheel(wheel_dir: &Path, order: &[usize]) -> PathBuf {
+        let metadata21 = metadata21();
+        let tags = ["py3-none-any".to_string()];
+        let mut writer =
+            WheelWriter::new("py3-none-any", wheel_dir, &metadata21, &tags, &[]).unwrap();
+
+        let entries: [(&str, &[u8], u32); 3] = [
+            ("hello/__init__.py", b"from .hello import *\n", 0o664),
+            ("hello/hello.abi3.so", b"\x7fELF", 0o775),
+            ("hello-0.1.0.data/scripts/hello", b"#!python\n", 0o700),
+        ];
+        for &i in order {
+            let (path, bytes, permissions) = entries[i];
+            writer
+                .add_bytes_with_permissions(path, bytes, permissions)
+                .unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_wheels_are_reproducible() {
+        env::set_var("SOURCE_DATE_EPOCH", "1700000000");
+        let (first_dir, second_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let first = write_wheel(first_dir.path(), &[0, 1, 2]);
+        let second = write_wheel(second_dir.path(), &[2, 1, 0]);
+        assert_eq!(fs::read(&first).unwrap(), fs::read(&second).unwrap());
+
+        let mut archive = ZipArchive::new(File::open(&first).unwrap()).unwrap();
+        let mut names = Vec::new();
+        for i in 0..archive.len() {
+            let entry = archive.by_index(i).unwrap();
+            names.push(entry.name().to_string());
+
+            // 2023-11-14 22:13:20 UTC, to the two seconds dos timestamps can represent
+            let mtime = entry.last_modified();
+            assert_eq!(
+                (mtime.year(), mtime.month(), mtime.day()),
+                (2023, 11, 14),
+                "{}",
+                entry.name()
+            );
+            assert_eq!(
+                (mtime.hour(), mtime.minute(), mtime.second()),
+                (22, 13, 20),
+                "{}",
+                entry.name()
+            );
+
+            let executable = entry.name().ends_with(".so") || entry.name().ends_with("/hello");
+            let permissions = if executable { 0o755 } else { 0o644 };
+            assert_eq!(
+                entry.unix_mode(),
+                Some(0o100000 | permissions),
+                "{}",
+                entry.name()
+            );
+        }
+
+        // Sorted, with the .dist-info directory at the end
+        assert_eq!(
+            names,
+            vec![
+                "hello-0.1.0.data/scripts/hello",
+                "hello/__init__.py",
+                "hello/hello.abi3.so",
+                "hello-0.1.0.dist-info/METADATA",
+                "hello-0.1.0.dist-info/WHEEL",
+                "hello-0.1.0.dist-info/RECORD",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_entries_are_only_added_once() {
+        let dir = TempDir::new().unwrap();
+        let metadata21 = metadata21();
+        let mut writer =
+            WheelWriter::new("py3-none-any", dir.path(), &metadata21, &[], &[]).unwrap();
+        writer
+            .add_bytes_with_permissions("hello/__init__.py", b"", 0o644)
+            .unwrap();
+        assert!(writer
+            .add_bytes_with_permissions("hello/__init__.py", b"", 0o644)
+            .is_err());
+    }
+
+    #[test]
+    fn test_zip_date_time_of_epochs() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(19_675), (2023, 11, 14));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+    }
+}